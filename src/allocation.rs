@@ -0,0 +1,219 @@
+//! Allocation of executable volume among orders at the marginal price level,
+//! orders with better price than marginal level are always filled in full.
+
+use crate::{
+    fixed::{PriceValue, QuantityValue},
+    orders::RegisteredOrder,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AllocationPolicy {
+    /// Fill orders in time priority: earlier epoch first, then arrival sequence
+    #[default]
+    PriceTime,
+    /// Share proportional to order quantity, rounded down,
+    /// leftover units go one per order in time priority
+    ProRata,
+    /// Equal share per order capped by order quantity,
+    /// leftover units go one per order in time priority
+    EqualSplit,
+}
+
+/// Allocation between displayed and hidden quantity of iceberg orders
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum IcebergAllocation {
+    /// Displayed quantities of the level are filled first, hidden quantities share the rest
+    #[default]
+    VisibleFirst,
+    /// Hidden quantity competes for volume as if it was displayed
+    Total,
+}
+
+/// Split `volume` among orders of the same price level, sharing displayed
+/// and hidden quantity of iceberg orders according to `iceberg` rule.
+/// Returns filled quantity per order in level order.
+pub fn allocate_level<P: PriceValue, Q: QuantityValue>(
+    policy: AllocationPolicy,
+    iceberg: IcebergAllocation,
+    level: &[RegisteredOrder<P, Q>],
+    volume: u64,
+) -> Vec<Q> {
+    if iceberg == IcebergAllocation::Total || level.iter().all(|order| order.hidden() == Q::ZERO) {
+        return allocate(policy, level, volume);
+    }
+    let displayed: u64 = level.iter().map(|order| order.displayed.to_raw()).sum();
+    let mut allocated = allocate_part(policy, level, volume.min(displayed), |order| {
+        order.displayed
+    });
+    if volume > displayed {
+        let hidden = allocate_part(policy, level, volume - displayed, RegisteredOrder::hidden);
+        for (allocated, hidden) in allocated.iter_mut().zip(hidden) {
+            *allocated += hidden;
+        }
+    }
+    allocated
+}
+
+/// Split `volume` among orders of the same price level,
+/// volume must be less than total quantity of the level.
+/// Returns filled quantity per order in level order.
+pub fn allocate<P: PriceValue, Q: QuantityValue>(
+    policy: AllocationPolicy,
+    level: &[RegisteredOrder<P, Q>],
+    volume: u64,
+) -> Vec<Q> {
+    let priority = time_priority(level);
+    let mut allocated = vec![0u64; level.len()];
+    let mut remaining = volume;
+    match policy {
+        AllocationPolicy::PriceTime => {
+            for &idx in priority.iter() {
+                let quantity = remaining.min(level[idx].quantity.to_raw());
+                allocated[idx] = quantity;
+                remaining -= quantity;
+            }
+        }
+        AllocationPolicy::ProRata => {
+            let total: u128 = level
+                .iter()
+                .map(|order| order.quantity.to_raw() as u128)
+                .sum();
+            for (idx, order) in level.iter().enumerate() {
+                let share = order.quantity.to_raw() as u128 * volume as u128 / total;
+                allocated[idx] = share as u64;
+                remaining -= share as u64;
+            }
+            // Rounding leaves less than one unit per order
+            for &idx in priority.iter().take(remaining as usize) {
+                allocated[idx] += 1;
+            }
+        }
+        AllocationPolicy::EqualSplit => {
+            let mut open = priority;
+            while remaining > 0 && !open.is_empty() {
+                let share = remaining / open.len() as u64;
+                if share == 0 {
+                    for &idx in open.iter().take(remaining as usize) {
+                        allocated[idx] += 1;
+                    }
+                    break;
+                }
+                open.retain(|&idx| {
+                    let quantity = share.min(level[idx].quantity.to_raw() - allocated[idx]);
+                    allocated[idx] += quantity;
+                    remaining -= quantity;
+                    allocated[idx] < level[idx].quantity.to_raw()
+                });
+            }
+        }
+    }
+    // Every share is bounded by quantity of its order
+    allocated
+        .into_iter()
+        .map(|quantity| Q::from_raw(quantity).expect("share fits order quantity"))
+        .collect()
+}
+
+/// Allocate over part of orders quantity, orders without such quantity get nothing
+fn allocate_part<P: PriceValue, Q: QuantityValue>(
+    policy: AllocationPolicy,
+    level: &[RegisteredOrder<P, Q>],
+    volume: u64,
+    quantity: impl Fn(&RegisteredOrder<P, Q>) -> Q,
+) -> Vec<Q> {
+    let mut allocated = vec![Q::ZERO; level.len()];
+    if volume == 0 {
+        return allocated;
+    }
+    let (indexes, part): (Vec<_>, Vec<_>) = level
+        .iter()
+        .enumerate()
+        .filter(|(_, order)| quantity(order) > Q::ZERO)
+        .map(|(idx, order)| {
+            let mut order = order.clone();
+            order.quantity = quantity(&order);
+            (idx, order)
+        })
+        .unzip();
+    for (idx, quantity) in indexes.into_iter().zip(allocate(policy, &part, volume)) {
+        allocated[idx] = quantity;
+    }
+    allocated
+}
+
+/// Indexes of orders sorted by epoch, then arrival sequence
+fn time_priority<P, Q>(level: &[RegisteredOrder<P, Q>]) -> Vec<usize> {
+    let mut priority: Vec<_> = (0..level.len()).collect();
+    priority.sort_by_key(|&idx| (level[idx].epoch, level[idx].sequence));
+    priority
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orders::{Epoch, Order, OrderType, RegisteredOrders};
+
+    fn test_level(orders: &[(Epoch, u32)]) -> Vec<RegisteredOrder> {
+        let mut registered = RegisteredOrders::default();
+        orders
+            .iter()
+            .map(|(epoch, quantity)| {
+                let order = Order::limit(0, OrderType::Buy, 100, *quantity);
+                registered.add_get_order(order, *epoch)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn allocate_price_time() {
+        let level = test_level(&[(1, 10), (0, 10), (1, 10)]);
+        assert_eq!(
+            allocate(AllocationPolicy::PriceTime, &level, 15),
+            vec![5, 10, 0]
+        );
+    }
+
+    #[test]
+    fn allocate_pro_rata() {
+        let level = test_level(&[(0, 10), (0, 20), (0, 30)]);
+        assert_eq!(
+            allocate(AllocationPolicy::ProRata, &level, 30),
+            vec![5, 10, 15]
+        );
+        let level = test_level(&[(1, 10), (0, 10), (0, 10)]);
+        assert_eq!(
+            allocate(AllocationPolicy::ProRata, &level, 10),
+            vec![3, 4, 3]
+        );
+    }
+
+    #[test]
+    fn allocate_iceberg() {
+        let mut registered = RegisteredOrders::default();
+        let level: Vec<_> = [Some(5), None]
+            .iter()
+            .map(|peak| {
+                let mut order = Order::limit(0, OrderType::Buy, 100, 20);
+                order.peak = *peak;
+                registered.add_get_order(order, 0)
+            })
+            .collect();
+        let allocated = |iceberg| allocate_level(AllocationPolicy::PriceTime, iceberg, &level, 30);
+        assert_eq!(allocated(IcebergAllocation::VisibleFirst), vec![10, 20]);
+        assert_eq!(allocated(IcebergAllocation::Total), vec![20, 10]);
+    }
+
+    #[test]
+    fn allocate_equal_split() {
+        let level = test_level(&[(0, 2), (0, 20), (0, 30)]);
+        assert_eq!(
+            allocate(AllocationPolicy::EqualSplit, &level, 30),
+            vec![2, 14, 14]
+        );
+        let level = test_level(&[(0, 10), (1, 10), (0, 10)]);
+        assert_eq!(
+            allocate(AllocationPolicy::EqualSplit, &level, 11),
+            vec![4, 3, 4]
+        );
+    }
+}
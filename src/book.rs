@@ -0,0 +1,529 @@
+//! Storage of one side of the order book, the auction is written against
+//! `OrderBookSide` so storage can be picked per instrument:
+//! -- `SortedOrders`, vector kept sorted in priority order
+//! -- `LevelOrders`, queues of orders per price level with cached total quantity
+//! -- `BucketOrders`, queue per tick of bounded price range with prefix sums of quantity
+//!
+//! Auction only needs orders in priority order and takes crossing ones from
+//! the front of the book, matches them apart and puts the rest back in front.
+
+use crate::{
+    fixed::{PriceValue, QuantityValue},
+    orders::{OrderId, OrderType, Price, Quantity, RegisteredOrder},
+    spec::{InstrumentSpec, SpecError},
+};
+use slotmap::SparseSecondaryMap;
+use std::fmt::Debug;
+
+/// Displayed quantity aggregated at a price of the book
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DepthLevel<P = Price> {
+    pub rate: P,
+    /// Sum of raw quantities
+    pub quantity: u64,
+    pub orders: usize,
+}
+
+/// Total quantity of orders at a price of the book
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LevelVolume<P = Price> {
+    pub rate: P,
+    /// Sum of raw quantities
+    pub quantity: u128,
+    pub orders: usize,
+    /// Market orders make a level of their own ahead of limit orders
+    pub market: bool,
+}
+
+/// Link to no slot
+const NIL: usize = usize::MAX;
+
+#[derive(Debug)]
+struct Node<P, Q> {
+    /// None while slot is free
+    order: Option<RegisteredOrder<P, Q>>,
+    prev: usize,
+    next: usize,
+}
+
+/// Orders of one level in time priority with their total quantity.
+/// Orders are linked in slots which stay put while they are queued,
+/// books index slot of each order to remove or amend it in O(1).
+#[derive(Debug)]
+pub(crate) struct OrderQueue<P, Q> {
+    nodes: Vec<Node<P, Q>>,
+    /// Slots of removed orders
+    free: Vec<usize>,
+    head: usize,
+    tail: usize,
+    len: usize,
+    /// Sum of raw quantities
+    pub(crate) quantity: u128,
+}
+
+/// Orders of the queue from the first one
+pub(crate) struct QueueIter<'a, P, Q> {
+    nodes: &'a [Node<P, Q>],
+    next: usize,
+}
+
+impl<P, Q> Default for OrderQueue<P, Q> {
+    fn default() -> Self {
+        Self {
+            nodes: Vec::new(),
+            free: Vec::new(),
+            head: NIL,
+            tail: NIL,
+            len: 0,
+            quantity: 0,
+        }
+    }
+}
+
+impl<P, Q> OrderQueue<P, Q> {
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[inline]
+    pub(crate) fn front(&self) -> Option<&RegisteredOrder<P, Q>> {
+        self.nodes.get(self.head)?.order.as_ref()
+    }
+
+    #[inline]
+    pub(crate) fn iter(&self) -> QueueIter<'_, P, Q> {
+        QueueIter {
+            nodes: &self.nodes,
+            next: self.head,
+        }
+    }
+}
+
+impl<P: PriceValue, Q: QuantityValue> OrderQueue<P, Q> {
+    /// Queue order behind orders of earlier epoch and arrival, usually at the back.
+    /// Returns slot of the order.
+    pub(crate) fn insert(&mut self, order: RegisteredOrder<P, Q>) -> usize {
+        let key = (order.epoch, order.sequence);
+        let mut prev = self.tail;
+        while prev != NIL {
+            let queued = self.nodes[prev].order.as_ref().expect("linked slot");
+            if (queued.epoch, queued.sequence) < key {
+                break;
+            }
+            prev = self.nodes[prev].prev;
+        }
+        self.link(order, prev)
+    }
+
+    /// Put order ahead of all queued ones, returns slot of the order
+    #[inline]
+    pub(crate) fn push_front(&mut self, order: RegisteredOrder<P, Q>) -> usize {
+        self.link(order, NIL)
+    }
+
+    #[inline]
+    pub(crate) fn pop_front(&mut self) -> Option<RegisteredOrder<P, Q>> {
+        match self.head {
+            NIL => None,
+            head => self.remove(head),
+        }
+    }
+
+    /// Remove order in `slot`
+    pub(crate) fn remove(&mut self, slot: usize) -> Option<RegisteredOrder<P, Q>> {
+        let node = self.nodes.get_mut(slot)?;
+        let order = node.order.take()?;
+        let (prev, next) = (node.prev, node.next);
+        match prev {
+            NIL => self.head = next,
+            prev => self.nodes[prev].next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => self.nodes[next].prev = prev,
+        }
+        self.len -= 1;
+        self.quantity -= order.quantity.to_raw() as u128;
+        if self.len == 0 {
+            // No slot is referenced any more, start over from the first one
+            self.nodes.clear();
+            self.free.clear();
+        } else {
+            self.free.push(slot);
+        }
+        Some(order)
+    }
+
+    /// Set quantity of order in `slot`, returns change of total quantity
+    pub(crate) fn amend(&mut self, slot: usize, quantity: Q) -> i128 {
+        match self
+            .nodes
+            .get_mut(slot)
+            .and_then(|node| node.order.as_mut())
+        {
+            Some(order) => {
+                let delta = quantity.to_raw() as i128 - order.quantity.to_raw() as i128;
+                order.set_quantity(quantity);
+                self.quantity = (self.quantity as i128 + delta) as u128;
+                delta
+            }
+            None => 0,
+        }
+    }
+
+    pub(crate) fn volume(&self, market: bool) -> Option<LevelVolume<P>> {
+        self.front().map(|first| LevelVolume {
+            rate: first.rate,
+            quantity: self.quantity,
+            orders: self.len,
+            market,
+        })
+    }
+
+    /// Link order behind slot `prev`, at the front if it is `NIL`
+    fn link(&mut self, order: RegisteredOrder<P, Q>, prev: usize) -> usize {
+        self.quantity += order.quantity.to_raw() as u128;
+        self.len += 1;
+        let next = match prev {
+            NIL => self.head,
+            prev => self.nodes[prev].next,
+        };
+        let node = Node {
+            order: Some(order),
+            prev,
+            next,
+        };
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.nodes[slot] = node;
+                slot
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        match prev {
+            NIL => self.head = slot,
+            prev => self.nodes[prev].next = slot,
+        }
+        match next {
+            NIL => self.tail = slot,
+            next => self.nodes[next].prev = slot,
+        }
+        slot
+    }
+}
+
+impl<'a, P, Q> Iterator for QueueIter<'a, P, Q> {
+    type Item = &'a RegisteredOrder<P, Q>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.nodes.get(self.next)?;
+        self.next = node.next;
+        node.order.as_ref()
+    }
+}
+
+/// Volumes of price levels of orders given in priority order
+pub fn level_volumes<'a, P: PriceValue, Q: QuantityValue>(
+    orders: impl Iterator<Item = &'a RegisteredOrder<P, Q>>,
+) -> Vec<LevelVolume<P>> {
+    let mut volumes: Vec<LevelVolume<P>> = Vec::new();
+    for order in orders {
+        match volumes.last_mut() {
+            Some(level) if level.rate == order.rate && level.market == order.is_market() => {
+                level.quantity += order.quantity.to_raw() as u128;
+                level.orders += 1;
+            }
+            _ => volumes.push(LevelVolume {
+                rate: order.rate,
+                quantity: order.quantity.to_raw() as u128,
+                orders: 1,
+                market: order.is_market(),
+            }),
+        }
+    }
+    volumes
+}
+
+/// Orders of one side of the book in price-time priority, see `order_priority`
+pub trait OrderBookSide<P: PriceValue = Price, Q: QuantityValue = Quantity>:
+    Debug + Send + Sync + Sized
+{
+    /// Orders from the best one
+    type Iter<'a>: Iterator<Item = &'a RegisteredOrder<P, Q>>
+    where
+        Self: 'a;
+
+    /// Empty book for orders conforming to `spec`, allocates nothing until orders are added.
+    /// Fails if the storage can not hold orders of the spec.
+    fn new(order_type: OrderType, spec: &InstrumentSpec<P, Q>) -> Result<Self, SpecError<P, Q>>;
+
+    fn order_type(&self) -> OrderType;
+
+    fn len(&self) -> usize;
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn iter(&self) -> Self::Iter<'_>;
+
+    /// Best order
+    #[inline]
+    fn first(&self) -> Option<&RegisteredOrder<P, Q>> {
+        self.iter().next()
+    }
+
+    /// Add orders of any priority, `orders` are left empty
+    /// unless some of them do not conform to spec the book was made for
+    fn insert_batch(&mut self, orders: &mut Vec<RegisteredOrder<P, Q>>);
+
+    fn remove_batch(&mut self, ids: &SparseSecondaryMap<OrderId, ()>);
+
+    /// Add new orders, then cancel orders of `cancels` among them and in the book
+    fn apply_batch(
+        &mut self,
+        adds: &mut Vec<RegisteredOrder<P, Q>>,
+        cancels: &SparseSecondaryMap<OrderId, ()>,
+    ) {
+        self.insert_batch(adds);
+        self.remove_batch(cancels);
+    }
+
+    /// Update quantity of orders in place, keeping their priority
+    fn amend_batch(&mut self, amends: &SparseSecondaryMap<OrderId, Q>);
+
+    /// Take best `count` orders out of the book in priority order
+    fn drain_prefix(&mut self, count: usize) -> Vec<RegisteredOrder<P, Q>>;
+
+    /// Put back orders in priority order which are all ahead of orders in the book,
+    /// as ones taken by `drain_prefix`
+    fn prepend_batch(&mut self, mut orders: Vec<RegisteredOrder<P, Q>>) {
+        self.insert_batch(&mut orders);
+    }
+
+    /// Volumes of price levels from the best one while `crosses` holds for their price
+    fn volumes_while(&self, crosses: impl Fn(P) -> bool) -> Vec<LevelVolume<P>> {
+        level_volumes(self.iter().take_while(|order| crosses(order.rate)))
+    }
+
+    /// Total quantity of orders at `rate` or better, market orders included
+    fn volume_at(&self, rate: P) -> u128 {
+        let order_type = self.order_type();
+        self.volumes_while(|price| match order_type {
+            OrderType::Buy => price >= rate,
+            OrderType::Sell => price <= rate,
+        })
+        .iter()
+        .map(|level| level.quantity)
+        .sum()
+    }
+
+    /// Best `levels` price levels as published, without market orders
+    /// and hidden quantity of iceberg orders
+    fn depth(&self, levels: usize) -> Vec<DepthLevel<P>> {
+        let mut depth: Vec<DepthLevel<P>> = Vec::with_capacity(levels);
+        for order in self.iter() {
+            if order.is_market() || order.displayed == Q::ZERO {
+                continue;
+            }
+            match depth.last_mut() {
+                Some(level) if level.rate == order.rate => {
+                    level.quantity += order.displayed.to_raw();
+                    level.orders += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            if depth.len() == levels {
+                break;
+            }
+            depth.push(DepthLevel {
+                rate: order.rate,
+                quantity: order.displayed.to_raw(),
+                orders: 1,
+            });
+        }
+        depth
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bucket_orders::BucketOrders,
+        level_orders::LevelOrders,
+        market::{indicative_match, market_match, MatchConfig, Trade},
+        orders::{Order, RegisteredOrders},
+        sorted_vec_orders::SortedOrders,
+    };
+    use nanorand::WyRand;
+
+    fn same_orders<B: OrderBookSide>(book: &B, sorted: &SortedOrders) -> bool {
+        book.len() == sorted.len() && book.iter().eq(sorted.iter())
+    }
+
+    fn same_volumes<B: OrderBookSide>(book: &B, sorted: &SortedOrders) -> bool {
+        (80..140).all(|rate| book.volume_at(rate) == sorted.volume_at(rate))
+    }
+
+    /// Every operation of the backend leaves the same orders as `SortedOrders`
+    fn conforms<B: OrderBookSide>(new_book: impl Fn(OrderType) -> B, seed: u64) {
+        for order_type in &[OrderType::Buy, OrderType::Sell] {
+            let mut registered = RegisteredOrders::default();
+            let mut rng = WyRand::new_seed(seed);
+            let mut batch = |epoch| -> Vec<_> {
+                (0..)
+                    .map(|i| match i % 100 {
+                        0 => Order::market(0, *order_type, 10),
+                        _ => Order::random(&mut rng, 100, 120, 0),
+                    })
+                    .filter(|order| order.order_type == *order_type)
+                    .map(|order| registered.add_get_order(order, epoch))
+                    .take(1_000)
+                    .collect()
+            };
+            let batches = [batch(0), batch(1), batch(1)];
+            let mut book = new_book(*order_type);
+            let mut sorted = SortedOrders::new(*order_type);
+            // Later batches with earlier epoch must still get ahead
+            for samples in batches.iter().rev() {
+                book.insert_batch(&mut samples.clone());
+                sorted.add_batch(&mut samples.clone());
+            }
+            assert!(same_orders(&book, &sorted));
+            assert!(same_volumes(&book, &sorted));
+
+            let cancels: SparseSecondaryMap<OrderId, ()> = sorted
+                .iter()
+                .step_by(3)
+                .map(|order| (order.id, ()))
+                .collect();
+            let amends: SparseSecondaryMap<OrderId, u32> = sorted
+                .iter()
+                .step_by(5)
+                .map(|order| (order.id, 1))
+                .collect();
+            book.remove_batch(&cancels);
+            sorted.remove_batch(&cancels);
+            book.amend_batch(&amends);
+            sorted.amend_batch(&amends);
+            assert!(same_orders(&book, &sorted));
+            assert!(same_volumes(&book, &sorted));
+            assert_eq!(
+                book.volumes_while(|rate| rate != 110),
+                sorted.volumes_while(|rate| rate != 110)
+            );
+            assert_eq!(book.depth(5), sorted.depth(5));
+
+            let best = book.drain_prefix(700);
+            assert_eq!(best, sorted.drain_prefix(700));
+            assert!(same_orders(&book, &sorted));
+            assert!(same_volumes(&book, &sorted));
+            book.prepend_batch(best.clone());
+            sorted.prepend_batch(best);
+            assert!(same_orders(&book, &sorted));
+            assert!(same_volumes(&book, &sorted));
+
+            let all = book.drain_prefix(book.len());
+            assert_eq!(all, sorted.drain_prefix(sorted.len()));
+            assert!(book.is_empty());
+            assert_eq!(book.first(), None);
+            assert_eq!(book.volume_at(110), 0);
+        }
+    }
+
+    /// Auction on the backend trades and leaves books as on `SortedOrders`
+    fn matches_sorted<B: OrderBookSide>(new_book: impl Fn(OrderType) -> B, seed: u64) {
+        let mut registered = RegisteredOrders::default();
+        let mut rng = WyRand::new_seed(seed);
+        let mut samples: Vec<_> = (0..2_000)
+            .map(|i| match i % 200 {
+                0 => Order::market(0, OrderType::Buy, 50),
+                1 => Order::market(0, OrderType::Sell, 50),
+                _ => Order::random(&mut rng, 95, 105, 4),
+            })
+            .map(|order| registered.add_get_order(order, 0))
+            .collect();
+        let mut books = (new_book(OrderType::Buy), new_book(OrderType::Sell));
+        let mut sorted_books = (
+            SortedOrders::new(OrderType::Buy),
+            SortedOrders::new(OrderType::Sell),
+        );
+        let (mut buy_samples, mut sell_samples): (Vec<_>, Vec<_>) = samples
+            .drain(..)
+            .partition(|order| order.order_type == OrderType::Buy);
+        books.0.insert_batch(&mut buy_samples.clone());
+        books.1.insert_batch(&mut sell_samples.clone());
+        sorted_books.0.add_batch(&mut buy_samples);
+        sorted_books.1.add_batch(&mut sell_samples);
+
+        let config = MatchConfig::default();
+        assert_eq!(
+            indicative_match(&books.0, &books.1, Some(100), &config),
+            indicative_match(&sorted_books.0, &sorted_books.1, Some(100), &config)
+        );
+        let by_book = market_match(books.0, books.1, Some(100), &config).unwrap();
+        let by_orders = market_match(sorted_books.0, sorted_books.1, Some(100), &config).unwrap();
+        assert!(by_book.traded_volume > 0);
+        assert_eq!(by_book.traded_rate, by_orders.traded_rate);
+        assert_eq!(by_book.traded_volume, by_orders.traded_volume);
+        let fills = |trades: &[Trade]| -> Vec<_> {
+            trades
+                .iter()
+                .map(|trade| (trade.order.id, trade.quantity))
+                .collect()
+        };
+        assert_eq!(fills(&by_book.trades), fills(&by_orders.trades));
+        assert!(same_orders(&by_book.open_bids, &by_orders.open_bids));
+        assert!(same_orders(&by_book.open_asks, &by_orders.open_asks));
+    }
+
+    #[test]
+    fn order_queue_slots() {
+        let mut registered = RegisteredOrders::default();
+        let mut queue = OrderQueue::default();
+        let orders: Vec<_> = (1..=3)
+            .map(|quantity| {
+                registered.add_get_order(Order::limit(0, OrderType::Buy, 100, quantity), 0)
+            })
+            .collect();
+        // Orders arriving out of sequence are queued in sequence
+        let mut slots: Vec<_> = orders
+            .iter()
+            .rev()
+            .map(|order| queue.insert(order.clone()))
+            .collect();
+        slots.reverse();
+        assert!(queue.iter().eq(orders.iter()));
+        assert_eq!(queue.remove(slots[1]).as_ref(), Some(&orders[1]));
+        assert_eq!(queue.remove(slots[1]), None);
+        assert_eq!(queue.quantity, 4);
+        // Free slot is reused, others stay put
+        assert_eq!(queue.push_front(orders[1].clone()), slots[1]);
+        assert_eq!(queue.amend(slots[2], 1), -2);
+        let quantities: Vec<_> = queue.iter().map(|order| order.quantity).collect();
+        assert_eq!(quantities, vec![2, 1, 1]);
+        assert_eq!(queue.pop_front().map(|order| order.id), Some(orders[1].id));
+        assert_eq!(queue.quantity, 2);
+    }
+
+    #[test]
+    fn level_orders_conform() {
+        conforms(LevelOrders::new, 3);
+        matches_sorted(LevelOrders::new, 4);
+    }
+
+    #[test]
+    fn bucket_orders_conform() {
+        let new_book = |order_type| BucketOrders::new(order_type, 80, 140, 1).unwrap();
+        conforms(new_book, 5);
+        matches_sorted(new_book, 6);
+    }
+}
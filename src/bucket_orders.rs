@@ -0,0 +1,421 @@
+//! Book side with one bucket per tick over the price range of instrument spec,
+//! for instruments with narrow bounded prices:
+//! -- Order finds its bucket by price in O(1), cancel finds bucket and slot by id in O(1)
+//! -- Market orders queue in a bucket ahead of all prices
+//! -- Fenwick tree over bucket quantities gives volume at or better than any price
+//!    in O(log n), which is the demand and supply curve of the auction
+//!
+//! Buckets and the tree are allocated with the first limit order, so empty book is cheap
+//! to create. Book is not made for spec with more than `MAX_BUCKETS` ticks, limit orders
+//! off tick or outside `[min_price, max_price]` of the spec are not taken.
+
+use crate::{
+    book::{LevelVolume, OrderBookSide, OrderQueue, QueueIter},
+    fixed::{PriceValue, QuantityValue},
+    orders::{OrderId, OrderType, Price, Quantity, RegisteredOrder},
+    spec::{InstrumentSpec, SpecError},
+};
+use slotmap::SparseSecondaryMap;
+use std::slice;
+
+/// Most ticks of price range the book allocates buckets for
+pub const MAX_BUCKETS: usize = 1 << 18;
+
+/// Bucket of market orders in the index
+const MARKET: usize = usize::MAX;
+
+#[derive(Debug)]
+pub struct BucketOrders<P = Price, Q = Quantity> {
+    order_type: OrderType,
+    /// Price of the first bucket in ticks
+    first_tick: i64,
+    tick_size: i64,
+    ticks: usize,
+    market: OrderQueue<P, Q>,
+    buckets: Vec<OrderQueue<P, Q>>,
+    /// Fenwick tree of bucket quantities
+    sums: Vec<i128>,
+    /// Lowest and highest bucket with orders
+    occupied: Option<(usize, usize)>,
+    /// Bucket and slot in its queue of every order in the book
+    index: SparseSecondaryMap<OrderId, (usize, usize)>,
+}
+
+/// Orders of the book from the best one
+pub struct Iter<'a, P, Q> {
+    bucket: QueueIter<'a, P, Q>,
+    buckets: slice::Iter<'a, OrderQueue<P, Q>>,
+    /// Bids walk buckets from the highest price
+    descending: bool,
+}
+
+impl<P: PriceValue, Q: QuantityValue> BucketOrders<P, Q> {
+    /// Buckets for every tick of `tick_size` within `[lowest, highest]`,
+    /// fails if there are more than `MAX_BUCKETS` of them
+    pub fn new(
+        order_type: OrderType,
+        lowest: P,
+        highest: P,
+        tick_size: P,
+    ) -> Result<Self, SpecError<P, Q>> {
+        let raw_tick = tick_size.to_raw();
+        if raw_tick <= 0 {
+            return Err(SpecError::NonPositiveTickSize { tick_size });
+        }
+        let first_tick = lowest.to_raw().div_euclid(raw_tick)
+            + (lowest.to_raw().rem_euclid(raw_tick) != 0) as i64;
+        let last_tick = highest.to_raw().div_euclid(raw_tick);
+        let ticks = (last_tick as i128 - first_tick as i128 + 1).max(0) as u128;
+        if ticks > MAX_BUCKETS as u128 {
+            return Err(SpecError::TooManyTicks {
+                ticks,
+                max_ticks: MAX_BUCKETS,
+            });
+        }
+        Ok(Self {
+            order_type,
+            first_tick,
+            tick_size: raw_tick,
+            ticks: ticks as usize,
+            market: Default::default(),
+            // Allocated with the first limit order
+            buckets: Vec::new(),
+            sums: Vec::new(),
+            occupied: None,
+            index: SparseSecondaryMap::new(),
+        })
+    }
+
+    /// Bucket of limit price, None if price is off tick or out of range
+    #[inline]
+    fn bucket(&self, rate: P) -> Option<usize> {
+        let raw = rate.to_raw();
+        if raw.rem_euclid(self.tick_size) != 0 {
+            return None;
+        }
+        let bucket = raw.div_euclid(self.tick_size) as i128 - self.first_tick as i128;
+        if bucket < 0 || bucket >= self.ticks as i128 {
+            return None;
+        }
+        Some(bucket as usize)
+    }
+
+    /// Bucket of order, None if it has no bucket
+    #[inline]
+    fn key(&self, order: &RegisteredOrder<P, Q>) -> Option<usize> {
+        if order.is_market() {
+            return Some(MARKET);
+        }
+        self.bucket(order.rate)
+    }
+
+    fn queue_mut(&mut self, key: usize) -> &mut OrderQueue<P, Q> {
+        if key == MARKET {
+            return &mut self.market;
+        }
+        if self.buckets.is_empty() {
+            self.buckets.resize_with(self.ticks, Default::default);
+            self.sums = vec![0; self.ticks + 1];
+        }
+        match self.occupied {
+            Some((lowest, highest)) => {
+                self.occupied = Some((lowest.min(key), highest.max(key)));
+            }
+            None => self.occupied = Some((key, key)),
+        }
+        &mut self.buckets[key]
+    }
+
+    /// Add change of bucket quantity to the tree
+    fn update(&mut self, key: usize, delta: i128) {
+        if key == MARKET || delta == 0 {
+            return;
+        }
+        let mut idx = key + 1;
+        while idx < self.sums.len() {
+            self.sums[idx] += delta;
+            idx += idx & idx.wrapping_neg();
+        }
+    }
+
+    /// Total quantity of buckets up to `key` included
+    fn prefix(&self, key: usize) -> i128 {
+        let mut total = 0;
+        let mut idx = key + 1;
+        while idx > 0 {
+            total += self.sums[idx];
+            idx -= idx & idx.wrapping_neg();
+        }
+        total
+    }
+
+    /// Narrow occupied range after bucket at its edge is left empty
+    fn shrink(&mut self) {
+        if let Some((mut lowest, mut highest)) = self.occupied {
+            while lowest <= highest && self.buckets[lowest].is_empty() {
+                lowest += 1;
+            }
+            while highest > lowest && self.buckets[highest].is_empty() {
+                highest -= 1;
+            }
+            self.occupied = if lowest <= highest {
+                Some((lowest, highest))
+            } else {
+                None
+            };
+        }
+    }
+
+    fn best_key(&self) -> Option<usize> {
+        if !self.market.is_empty() {
+            return Some(MARKET);
+        }
+        let (lowest, highest) = self.occupied?;
+        Some(match self.order_type {
+            OrderType::Buy => highest,
+            OrderType::Sell => lowest,
+        })
+    }
+
+    /// Occupied buckets from the best one
+    fn occupied_buckets(&self) -> Box<dyn Iterator<Item = &OrderQueue<P, Q>> + '_> {
+        let buckets = match self.occupied {
+            Some((lowest, highest)) => &self.buckets[lowest..=highest],
+            None => &[],
+        };
+        match self.order_type {
+            OrderType::Buy => Box::new(buckets.iter().rev()),
+            OrderType::Sell => Box::new(buckets.iter()),
+        }
+    }
+}
+
+impl<P: PriceValue, Q: QuantityValue> OrderBookSide<P, Q> for BucketOrders<P, Q> {
+    type Iter<'a> = Iter<'a, P, Q>;
+
+    #[inline]
+    fn new(order_type: OrderType, spec: &InstrumentSpec<P, Q>) -> Result<Self, SpecError<P, Q>> {
+        BucketOrders::new(order_type, spec.min_price, spec.max_price, spec.tick_size)
+    }
+
+    #[inline]
+    fn order_type(&self) -> OrderType {
+        self.order_type
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    fn iter(&self) -> Self::Iter<'_> {
+        let buckets = match self.occupied {
+            Some((lowest, highest)) => &self.buckets[lowest..=highest],
+            None => &[],
+        };
+        Iter {
+            bucket: self.market.iter(),
+            buckets: buckets.iter(),
+            descending: self.order_type == OrderType::Buy,
+        }
+    }
+
+    /// Orders off tick or out of range of buckets are left in `orders`
+    fn insert_batch(&mut self, orders: &mut Vec<RegisteredOrder<P, Q>>) {
+        for order in std::mem::take(orders) {
+            let key = match self.key(&order) {
+                Some(key) => key,
+                None => {
+                    orders.push(order);
+                    continue;
+                }
+            };
+            let id = order.id;
+            let quantity = order.quantity.to_raw() as i128;
+            let slot = self.queue_mut(key).insert(order);
+            self.index.insert(id, (key, slot));
+            self.update(key, quantity);
+        }
+    }
+
+    fn remove_batch(&mut self, ids: &SparseSecondaryMap<OrderId, ()>) {
+        for id in ids.keys() {
+            if let Some((key, slot)) = self.index.remove(id) {
+                if let Some(order) = self.queue_mut(key).remove(slot) {
+                    self.update(key, -(order.quantity.to_raw() as i128));
+                }
+            }
+        }
+        self.shrink();
+    }
+
+    fn amend_batch(&mut self, amends: &SparseSecondaryMap<OrderId, Q>) {
+        for (id, quantity) in amends.iter() {
+            if let Some((key, slot)) = self.index.get(id).copied() {
+                let delta = self.queue_mut(key).amend(slot, *quantity);
+                self.update(key, delta);
+            }
+        }
+        self.shrink();
+    }
+
+    fn drain_prefix(&mut self, count: usize) -> Vec<RegisteredOrder<P, Q>> {
+        let mut drained = Vec::with_capacity(count.min(self.len()));
+        while drained.len() < count {
+            let key = match self.best_key() {
+                Some(key) => key,
+                None => break,
+            };
+            let mut delta = 0;
+            while drained.len() < count {
+                match self.queue_mut(key).pop_front() {
+                    Some(order) => {
+                        delta -= order.quantity.to_raw() as i128;
+                        self.index.remove(order.id);
+                        drained.push(order);
+                    }
+                    None => break,
+                }
+            }
+            self.update(key, delta);
+            self.shrink();
+        }
+        drained
+    }
+
+    fn prepend_batch(&mut self, orders: Vec<RegisteredOrder<P, Q>>) {
+        for order in orders.into_iter().rev() {
+            // Orders taken from the book have their bucket
+            let key = match self.key(&order) {
+                Some(key) => key,
+                None => continue,
+            };
+            let id = order.id;
+            let quantity = order.quantity.to_raw() as i128;
+            let slot = self.queue_mut(key).push_front(order);
+            self.index.insert(id, (key, slot));
+            self.update(key, quantity);
+        }
+    }
+
+    fn volumes_while(&self, crosses: impl Fn(P) -> bool) -> Vec<LevelVolume<P>> {
+        let limits = self
+            .occupied_buckets()
+            .filter_map(|bucket| bucket.volume(false));
+        self.market
+            .volume(true)
+            .into_iter()
+            .chain(limits)
+            .take_while(|level| crosses(level.rate))
+            .collect()
+    }
+
+    fn volume_at(&self, rate: P) -> u128 {
+        let market = self.market.quantity;
+        if self.buckets.is_empty() {
+            return market;
+        }
+        // Buckets at or better than rate are a prefix or a suffix of the range
+        let raw = rate.to_raw() as i128;
+        let tick = self.tick_size as i128;
+        let first = self.first_tick as i128;
+        let total = self.prefix(self.ticks - 1);
+        let limits = match self.order_type {
+            OrderType::Buy => {
+                let from = (raw.div_euclid(tick) + (raw.rem_euclid(tick) != 0) as i128) - first;
+                match from {
+                    from if from <= 0 => total,
+                    from if from >= self.ticks as i128 => 0,
+                    from => total - self.prefix(from as usize - 1),
+                }
+            }
+            OrderType::Sell => match raw.div_euclid(tick) - first {
+                to if to < 0 => 0,
+                to => self.prefix((to as usize).min(self.ticks - 1)),
+            },
+        };
+        market + limits as u128
+    }
+}
+
+impl<'a, P, Q> Iterator for Iter<'a, P, Q> {
+    type Item = &'a RegisteredOrder<P, Q>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(order) = self.bucket.next() {
+                return Some(order);
+            }
+            let queue = if self.descending {
+                self.buckets.next_back()
+            } else {
+                self.buckets.next()
+            }?;
+            self.bucket = queue.iter();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orders::{Order, RegisteredOrders};
+
+    #[test]
+    fn bucket_orders_volume_between_ticks() {
+        let mut registered = RegisteredOrders::default();
+        let mut bids = BucketOrders::new(OrderType::Buy, 98, 120, 5).unwrap();
+        let mut asks = BucketOrders::new(OrderType::Sell, 98, 120, 5).unwrap();
+        let mut samples: Vec<_> = vec![
+            Order::limit(0, OrderType::Buy, 100, 10),
+            Order::limit(0, OrderType::Buy, 110, 20),
+            Order::market(0, OrderType::Buy, 5),
+        ]
+        .into_iter()
+        .map(|order| registered.add_get_order(order, 0))
+        .collect();
+        bids.insert_batch(&mut samples);
+        let mut samples: Vec<_> = vec![
+            Order::limit(0, OrderType::Sell, 100, 10),
+            Order::limit(0, OrderType::Sell, 120, 20),
+        ]
+        .into_iter()
+        .map(|order| registered.add_get_order(order, 0))
+        .collect();
+        asks.insert_batch(&mut samples);
+        assert!(samples.is_empty());
+
+        assert_eq!(bids.volume_at(90), 35);
+        assert_eq!(bids.volume_at(101), 25);
+        assert_eq!(bids.volume_at(110), 25);
+        assert_eq!(bids.volume_at(111), 5);
+        assert_eq!(bids.volume_at(200), 5);
+        assert_eq!(asks.volume_at(90), 0);
+        assert_eq!(asks.volume_at(119), 10);
+        assert_eq!(asks.volume_at(120), 30);
+        assert_eq!(asks.volume_at(200), 30);
+    }
+
+    #[test]
+    fn bucket_orders_reject_wide_spec() {
+        let book: Result<BucketOrders, _> =
+            OrderBookSide::new(OrderType::Buy, &InstrumentSpec::default());
+        assert!(matches!(book, Err(SpecError::TooManyTicks { .. })));
+
+        // Orders outside of buckets are handed back
+        let mut registered = RegisteredOrders::default();
+        let mut bids = BucketOrders::new(OrderType::Buy, 100, 120, 5).unwrap();
+        let mut samples: Vec<_> = vec![
+            Order::limit(0, OrderType::Buy, 101, 10),
+            Order::limit(0, OrderType::Buy, 125, 10),
+            Order::limit(0, OrderType::Buy, 110, 10),
+        ]
+        .into_iter()
+        .map(|order| registered.add_get_order(order, 0))
+        .collect();
+        bids.insert_batch(&mut samples);
+        assert_eq!(bids.len(), 1);
+        let rates: Vec<_> = samples.iter().map(|order| order.rate).collect();
+        assert_eq!(rates, vec![101, 125]);
+    }
+}
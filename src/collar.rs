@@ -0,0 +1,159 @@
+//! Price collar of the auction, a circuit breaker for volatile markets.
+//! Clearing price outside of any configured band interrupts the auction:
+//! -- Static band is set around reference price given by venue, e.g. previous close
+//! -- Dynamic band moves with the last traded rate
+//!
+//! Interrupted auction does not trade, the engine then extends the auction
+//! for configured number of epochs or suspends it until resumed.
+
+use crate::{
+    fixed::PriceValue,
+    orders::{Epoch, Price},
+};
+
+/// Tolerance of band in basis points of reference price, 100 is 1%
+pub type BasisPoints = u32;
+
+/// Prices within `tolerance` from `reference`, bounds included
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PriceBand<P = Price> {
+    pub reference: P,
+    pub tolerance: BasisPoints,
+}
+
+/// Band which clearing price fell out of
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CollarBand {
+    Static,
+    Dynamic,
+}
+
+/// What engine does after interruption
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreachAction {
+    /// Auctions of following epochs are skipped, books keep collecting orders
+    Extend(Epoch),
+    /// Auctions are skipped until engine is resumed
+    Suspend,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PriceCollar<P = Price> {
+    pub static_band: Option<PriceBand<P>>,
+    /// Tolerance around the last traded rate, not applied before the first trade
+    pub dynamic_tolerance: Option<BasisPoints>,
+    pub on_breach: BreachAction,
+}
+
+/// Auction interrupted as clearing `rate` is outside `[lowest, highest]` of `band`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interruption<P = Price> {
+    pub rate: P,
+    pub band: CollarBand,
+    pub lowest: P,
+    pub highest: P,
+    pub action: BreachAction,
+}
+
+/// Whether the engine runs auctions
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TradingState {
+    #[default]
+    Open,
+    /// Auctions resume from given epoch
+    Extended { until: Epoch },
+    /// Auctions resume once engine is resumed
+    Suspended,
+}
+
+impl<P: PriceValue> Default for PriceCollar<P> {
+    /// No bands, breach of band set later extends auction by one epoch
+    fn default() -> Self {
+        Self {
+            static_band: None,
+            dynamic_tolerance: None,
+            on_breach: BreachAction::Extend(1),
+        }
+    }
+}
+
+impl<P: PriceValue> PriceBand<P> {
+    /// Lowest and highest price of the band, limited by range of the price type
+    pub fn bounds(&self) -> (P, P) {
+        let reference = self.reference.to_raw() as i128;
+        let deviation = reference.abs() * self.tolerance as i128 / 10_000;
+        let bound = |raw: i128| {
+            let raw = raw.clamp(P::MIN.to_raw() as i128, P::MAX.to_raw() as i128);
+            P::from_raw(raw as i64).expect("clamped to price range")
+        };
+        (bound(reference - deviation), bound(reference + deviation))
+    }
+}
+
+impl<P: PriceValue> PriceCollar<P> {
+    /// Interruption if clearing `rate` is outside of static band,
+    /// or outside of dynamic band around `last_rate`
+    pub fn check(&self, rate: P, last_rate: Option<P>) -> Option<Interruption<P>> {
+        let dynamic_band = self
+            .dynamic_tolerance
+            .zip(last_rate)
+            .map(|(tolerance, reference)| PriceBand {
+                reference,
+                tolerance,
+            });
+        [
+            (CollarBand::Static, self.static_band),
+            (CollarBand::Dynamic, dynamic_band),
+        ]
+        .iter()
+        .filter_map(|(kind, band)| Some((*kind, (*band)?.bounds())))
+        .find(|(_, (lowest, highest))| rate < *lowest || rate > *highest)
+        .map(|(band, (lowest, highest))| Interruption {
+            rate,
+            band,
+            lowest,
+            highest,
+            action: self.on_breach,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn price_collar_bands() {
+        let band = PriceBand {
+            reference: 1_000,
+            tolerance: 250,
+        };
+        assert_eq!(band.bounds(), (975, 1_025));
+        let wide = PriceBand {
+            reference: i32::MAX - 1,
+            tolerance: 1_000,
+        };
+        assert_eq!(wide.bounds().1, i32::MAX);
+
+        let collar = PriceCollar {
+            static_band: Some(band),
+            dynamic_tolerance: Some(100),
+            on_breach: BreachAction::Suspend,
+        };
+        assert_eq!(collar.check(1_020, None), None);
+        assert_eq!(collar.check(1_020, Some(1_015)), None);
+        assert_eq!(
+            collar.check(1_030, Some(1_025)),
+            Some(Interruption {
+                rate: 1_030,
+                band: CollarBand::Static,
+                lowest: 975,
+                highest: 1_025,
+                action: BreachAction::Suspend,
+            })
+        );
+        let interruption = collar.check(980, Some(1_000)).unwrap();
+        assert_eq!(interruption.band, CollarBand::Dynamic);
+        assert_eq!((interruption.lowest, interruption.highest), (990, 1_010));
+    }
+}
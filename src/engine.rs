@@ -0,0 +1,932 @@
+//! Auction engine glues together order registry, sorted books and market matching:
+//! -- Register order requests and route them to Buy / Sell batch
+//! -- Flush batches into sorted books
+//! -- Run auction on sorted books and clear filled orders from registry
+//! -- Skip auctions while extended or suspended after volatility interruption
+
+use crate::{
+    book::OrderBookSide,
+    collar::{BreachAction, TradingState},
+    fixed::{PriceValue, QuantityValue},
+    market::{
+        indicative_match, market_match, IndicativeMatch, MarketMatchResult, MatchConfig,
+        MatchErrorKind, Mechanism, SelfTrade,
+    },
+    mcafee::mcafee_match,
+    orders::{
+        AccountId, Epoch, ModifyResult, Order, OrderId, OrderIds, OrderType, Price, Quantity,
+        RegisteredOrder, RegisteredOrders, TimeInForce,
+    },
+    sorted_vec_orders::SortedOrders,
+    spec::{Rejection, SpecError},
+};
+use slotmap::SparseSecondaryMap;
+
+#[derive(Debug, Clone)]
+pub enum OrderRequest<P = Price, Q = Quantity> {
+    CancelOrder(OrderId),
+    /// Cancel every live order of the account
+    CancelAll(AccountId),
+    ModifyOrder(RegisteredOrder<P, Q>),
+    AddOrder(Order<P, Q>),
+}
+
+/// Immediate outcome of submitted request, books are updated on next flush
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubmitResult<P = Price, Q = Quantity> {
+    Added(RegisteredOrder<P, Q>),
+    Cancelled(RegisteredOrder<P, Q>),
+    CancelledAll(Vec<RegisteredOrder<P, Q>>),
+    Modified(ModifyResult<P, Q>),
+    UnknownOrder,
+    /// Instrument is not listed or request is routed to another instrument
+    UnknownInstrument,
+    /// Order is good till epoch which has already passed
+    Expired(Order<P, Q>),
+    /// New or amended order does not conform to instrument spec
+    Rejected(Rejection<P, Q>),
+}
+
+/// Outcome of auction run by the engine
+pub type AuctionResult<P = Price, Q = Quantity, B = SortedOrders<P, Q>> =
+    Result<MarketMatchResult<P, Q, B>, MatchErrorKind<P>>;
+
+/// Engine of one instrument, books are kept in storage `B`
+pub struct AuctionEngine<P = Price, Q = Quantity, B = SortedOrders<P, Q>> {
+    orders: RegisteredOrders<P, Q>,
+    bids: B,
+    asks: B,
+    buy_batch: Vec<RegisteredOrder<P, Q>>,
+    sell_batch: Vec<RegisteredOrder<P, Q>>,
+    cancel_ids: SparseSecondaryMap<OrderId, ()>,
+    requeued: SparseSecondaryMap<OrderId, RegisteredOrder<P, Q>>,
+    amends: SparseSecondaryMap<OrderId, Q>,
+    epoch: Epoch,
+    /// Last traded rate, used as reference price for next auction
+    reference_price: Option<P>,
+    /// Orders removed by time in force after the last auction
+    expired: Vec<RegisteredOrder<P, Q>>,
+    /// Dormant stop orders, registered but kept out of the books
+    stops: SparseSecondaryMap<OrderId, ()>,
+    /// Stop orders triggered by the last auction, queued for the next one
+    triggered: Vec<RegisteredOrder<P, Q>>,
+    /// Auctions are skipped unless open
+    state: TradingState,
+    config: MatchConfig<P, Q>,
+}
+
+impl<P: PriceValue, Q: QuantityValue> Default for AuctionEngine<P, Q> {
+    fn default() -> Self {
+        Self::with_storage(Default::default()).expect("default spec is valid")
+    }
+}
+
+impl<P: PriceValue, Q: QuantityValue> AuctionEngine<P, Q> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_config(config: MatchConfig<P, Q>) -> Result<Self, SpecError<P, Q>> {
+        Self::with_storage(config)
+    }
+}
+
+impl<P: PriceValue, Q: QuantityValue, B: OrderBookSide<P, Q>> AuctionEngine<P, Q, B> {
+    /// Engine with books kept in storage given by type, set up for instrument spec of `config`,
+    /// fails if the spec is not valid or the storage can not hold its orders
+    pub fn with_storage(config: MatchConfig<P, Q>) -> Result<Self, SpecError<P, Q>> {
+        config.spec.validate()?;
+        Ok(Self {
+            orders: Default::default(),
+            bids: B::new(OrderType::Buy, &config.spec)?,
+            asks: B::new(OrderType::Sell, &config.spec)?,
+            buy_batch: Default::default(),
+            sell_batch: Default::default(),
+            cancel_ids: Default::default(),
+            requeued: Default::default(),
+            amends: Default::default(),
+            epoch: 0,
+            reference_price: None,
+            expired: Vec::new(),
+            stops: Default::default(),
+            triggered: Vec::new(),
+            state: TradingState::Open,
+            config,
+        })
+    }
+
+    /// Take ids of new orders from key space shared with other engines,
+    /// so that requests for orders of other engines are not taken for own ones
+    pub fn with_order_ids(mut self, ids: OrderIds) -> Self {
+        debug_assert!(self.orders.is_empty(), "engine already has orders");
+        self.orders = RegisteredOrders::with_ids(ids);
+        self
+    }
+
+    #[inline]
+    pub fn epoch(&self) -> Epoch {
+        self.epoch
+    }
+
+    #[inline]
+    pub fn reference_price(&self) -> Option<P> {
+        self.reference_price
+    }
+
+    /// Orders removed by time in force after the last auction, whether it traded or not
+    #[inline]
+    pub fn expired(&self) -> &[RegisteredOrder<P, Q>] {
+        &self.expired
+    }
+
+    /// Stop orders triggered by traded rate of the last auction,
+    /// they take part in auctions from the new epoch
+    #[inline]
+    pub fn triggered(&self) -> &[RegisteredOrder<P, Q>] {
+        &self.triggered
+    }
+
+    #[inline]
+    pub fn state(&self) -> TradingState {
+        self.state
+    }
+
+    /// Resume auctions after suspension, or before extension is over
+    #[inline]
+    pub fn resume(&mut self) {
+        self.state = TradingState::Open;
+    }
+
+    /// Number of dormant stop orders
+    #[inline]
+    pub fn stops(&self) -> usize {
+        self.stops.len()
+    }
+
+    #[inline]
+    pub fn orders(&self) -> &RegisteredOrders<P, Q> {
+        &self.orders
+    }
+
+    #[inline]
+    pub fn bids(&self) -> &B {
+        &self.bids
+    }
+
+    #[inline]
+    pub fn asks(&self) -> &B {
+        &self.asks
+    }
+
+    /// Number of new orders waiting for flush
+    #[inline]
+    pub fn pending(&self) -> usize {
+        self.buy_batch.len() + self.sell_batch.len()
+    }
+
+    /// Register request and add it to batch for processing
+    pub fn submit(&mut self, request: OrderRequest<P, Q>) -> SubmitResult<P, Q> {
+        match request {
+            OrderRequest::CancelOrder(id) => match self.orders.remove_order(id) {
+                Some(order) => {
+                    self.stops.remove(id);
+                    self.requeued.remove(id);
+                    self.amends.remove(id);
+                    self.cancel_ids.insert(id, ());
+                    SubmitResult::Cancelled(order)
+                }
+                None => SubmitResult::UnknownOrder,
+            },
+            OrderRequest::CancelAll(account) => {
+                let cancelled = self.orders.cancel_all(account);
+                for order in cancelled.iter() {
+                    self.stops.remove(order.id);
+                    self.requeued.remove(order.id);
+                    self.amends.remove(order.id);
+                    self.cancel_ids.insert(order.id, ());
+                }
+                SubmitResult::CancelledAll(cancelled)
+            }
+            OrderRequest::AddOrder(order) => {
+                if let Err(rejection) = self.config.spec.validate_order(&order) {
+                    return SubmitResult::Rejected(rejection);
+                }
+                if let TimeInForce::GoodTillEpoch(last) = order.time_in_force {
+                    if last < self.epoch {
+                        return SubmitResult::Expired(order);
+                    }
+                }
+                let registered = self.orders.add_get_order(order, self.epoch);
+                if registered.is_stop() {
+                    self.stops.insert(registered.id, ());
+                } else {
+                    self.queue(registered.clone());
+                }
+                SubmitResult::Added(registered)
+            }
+            OrderRequest::ModifyOrder(order) => {
+                if let Err(rejection) = self.config.spec.validate_amend(&order) {
+                    return SubmitResult::Rejected(rejection);
+                }
+                let result = self.orders.modify_order(order, self.epoch);
+                match &result {
+                    // Dormant stop order is only amended in registry
+                    ModifyResult::Reduced(order) | ModifyResult::Requeued(order)
+                        if self.stops.contains_key(order.id) => {}
+                    ModifyResult::Reduced(order) => {
+                        if let Some(pending) = self.requeued.get_mut(order.id) {
+                            pending.set_quantity(order.quantity);
+                        } else {
+                            self.amends.insert(order.id, order.quantity);
+                        }
+                    }
+                    ModifyResult::Requeued(order) => {
+                        self.amends.remove(order.id);
+                        self.cancel_ids.insert(order.id, ());
+                        self.requeued.insert(order.id, order.clone());
+                    }
+                    _ => {}
+                }
+                SubmitResult::Modified(result)
+            }
+        }
+    }
+
+    #[inline]
+    fn queue(&mut self, order: RegisteredOrder<P, Q>) {
+        match order.order_type {
+            OrderType::Buy => self.buy_batch.push(order),
+            OrderType::Sell => self.sell_batch.push(order),
+        }
+    }
+
+    /// Apply pending batches to sorted books
+    pub fn flush(&mut self) {
+        let Self {
+            bids,
+            asks,
+            buy_batch,
+            sell_batch,
+            cancel_ids,
+            requeued,
+            amends,
+            ..
+        } = self;
+        rayon::join(
+            || flush_batch(bids, buy_batch, cancel_ids, requeued, amends),
+            || flush_batch(asks, sell_batch, cancel_ids, requeued, amends),
+        );
+        cancel_ids.clear();
+        requeued.clear();
+        amends.clear();
+    }
+
+    /// Price, volume and imbalance the next auction would have on flushed books,
+    /// as by call auction rules whatever the configured mechanism
+    pub fn indicative(&self) -> Result<IndicativeMatch<P>, MatchErrorKind<P>> {
+        indicative_match(&self.bids, &self.asks, self.reference_price, &self.config)
+    }
+
+    /// Flush pending batches, match the market and start new epoch.
+    /// Open books stay in the engine, `open_bids` and `open_asks` of result are left empty.
+    /// Halted engine only starts new epoch, orders for the next auction wait for it
+    /// and only orders past their last epoch expire. Interrupted auction is treated alike.
+    pub fn run_auction(&mut self) -> AuctionResult<P, Q, B> {
+        self.flush();
+        if let TradingState::Extended { until } = self.state {
+            if self.epoch >= until {
+                self.state = TradingState::Open;
+            }
+        }
+        if self.state != TradingState::Open {
+            let epoch = self.epoch;
+            self.epoch += 1;
+            self.expire_orders(epoch, false);
+            self.triggered.clear();
+            return Err(MatchErrorKind::Halted(self.state));
+        }
+        // Empty books stand in while the auction owns the books, they do not allocate
+        let spec = &self.config.spec;
+        let stand_in = |order_type| B::new(order_type, spec).expect("spec accepted by storage");
+        let bids = std::mem::replace(&mut self.bids, stand_in(OrderType::Buy));
+        let asks = std::mem::replace(&mut self.asks, stand_in(OrderType::Sell));
+        let match_result = match self.config.mechanism {
+            Mechanism::CallAuction => market_match(bids, asks, self.reference_price, &self.config),
+            Mechanism::McAfee => mcafee_match(bids, asks, self.reference_price, &self.config),
+        };
+        let epoch = self.epoch;
+        self.epoch += 1;
+        let mut match_result = match match_result {
+            Ok(match_result) => match_result,
+            Err(error) => {
+                if let MatchErrorKind::VolatilityInterruption(interruption) = error.kind {
+                    self.state = match interruption.action {
+                        BreachAction::Extend(epochs) => TradingState::Extended {
+                            until: self.epoch.saturating_add(epochs),
+                        },
+                        BreachAction::Suspend => TradingState::Suspended,
+                    };
+                }
+                self.bids = error.open_bids;
+                self.asks = error.open_asks;
+                self.apply_self_trades(&error.self_trades);
+                // Interrupted auction goes on once the engine opens again
+                self.expire_orders(epoch, self.state == TradingState::Open);
+                self.triggered.clear();
+                return Err(error.kind);
+            }
+        };
+        std::mem::swap(&mut self.bids, &mut match_result.open_bids);
+        std::mem::swap(&mut self.asks, &mut match_result.open_asks);
+
+        // Clear all orders processed in auction
+        self.orders.clear_filled();
+        self.apply_self_trades(&match_result.self_trades);
+        for deal in match_result.trades.iter() {
+            self.orders.fill_order(deal.order.id, deal.quantity);
+        }
+        self.refresh_icebergs();
+        if match_result.traded_rate.is_some() {
+            self.reference_price = match_result.traded_rate;
+        }
+        self.expire_orders(epoch, true);
+        match match_result.traded_rate {
+            Some(rate) => self.trigger_stops(rate),
+            None => self.triggered.clear(),
+        }
+        Ok(match_result)
+    }
+
+    /// Convert stop orders triggered by traded `rate` in arrival order
+    /// and queue them for the new epoch
+    fn trigger_stops(&mut self, rate: P) {
+        let mut triggered: Vec<_> = self
+            .stops
+            .keys()
+            .filter(|id| self.orders[*id].triggered_by(rate))
+            .map(|id| (self.orders[id].sequence, id))
+            .collect();
+        triggered.sort_unstable();
+        self.triggered.clear();
+        for (_, id) in triggered {
+            self.stops.remove(id);
+            if let Some(order) = self.orders.trigger_order(id, self.epoch) {
+                self.triggered.push(order.clone());
+                self.queue(order);
+            }
+        }
+    }
+
+    /// Display next tranche of iceberg orders with executed displayed quantity,
+    /// they are queued again as of new epoch
+    fn refresh_icebergs(&mut self) {
+        let Self {
+            orders,
+            bids,
+            asks,
+            epoch,
+            ..
+        } = self;
+        for book in [bids, asks] {
+            // Refresh in book order to keep relative priority deterministic
+            let mut refreshed: Vec<_> = book
+                .iter()
+                .filter(|order| order.needs_refresh())
+                .filter_map(|order| orders.refresh_order(order.id, *epoch))
+                .collect();
+            if refreshed.is_empty() {
+                continue;
+            }
+            let exhausted: SparseSecondaryMap<OrderId, ()> =
+                refreshed.iter().map(|order| (order.id, ())).collect();
+            book.remove_batch(&exhausted);
+            book.insert_batch(&mut refreshed);
+        }
+    }
+
+    /// Remove orders which can not take part in auctions after `epoch`,
+    /// orders for the next auction only leave once it `concluded`
+    fn expire_orders(&mut self, epoch: Epoch, concluded: bool) {
+        let mut expired = Vec::new();
+        for book in [&mut self.bids, &mut self.asks] {
+            let expires: Vec<_> = book
+                .iter()
+                .filter(|order| {
+                    if concluded {
+                        order.expires_after(epoch)
+                    } else {
+                        order.outlives(epoch)
+                    }
+                })
+                .cloned()
+                .collect();
+            if !expires.is_empty() {
+                let ids: SparseSecondaryMap<OrderId, ()> =
+                    expires.iter().map(|order| (order.id, ())).collect();
+                book.remove_batch(&ids);
+                expired.extend(expires);
+            }
+        }
+        // Dormant stop orders only expire by their last epoch
+        let stops: Vec<_> = self
+            .stops
+            .keys()
+            .filter(|id| self.orders[*id].outlives(epoch))
+            .collect();
+        for id in stops {
+            self.stops.remove(id);
+            expired.push(self.orders[id].clone());
+        }
+        for order in expired.iter() {
+            self.orders.remove_order(order.id);
+        }
+        self.expired = expired;
+    }
+
+    /// Reflect orders cancelled or reduced by self trade prevention in registry
+    fn apply_self_trades(&mut self, self_trades: &[SelfTrade<P, Q>]) {
+        for self_trade in self_trades {
+            let mut order = self_trade.order.clone();
+            order.quantity -= self_trade.reduced;
+            if order.quantity == Q::ZERO {
+                self.orders.remove_order(order.id);
+            } else {
+                self.orders.modify_order(order, self.epoch);
+            }
+        }
+    }
+}
+
+/// Apply pending batch to the book: new orders, then cancels,
+/// then requeued amends, then in place quantity reductions
+fn flush_batch<P: PriceValue, Q: QuantityValue, B: OrderBookSide<P, Q>>(
+    book: &mut B,
+    batch: &mut Vec<RegisteredOrder<P, Q>>,
+    cancel_ids: &SparseSecondaryMap<OrderId, ()>,
+    requeued: &SparseSecondaryMap<OrderId, RegisteredOrder<P, Q>>,
+    amends: &SparseSecondaryMap<OrderId, Q>,
+) {
+    book.apply_batch(batch, cancel_ids);
+    // Orders conform to spec the book is made for, so the book takes all of them
+    debug_assert!(batch.is_empty(), "book rejected orders conforming to spec");
+    batch.clear();
+    let mut requeued: Vec<_> = requeued
+        .values()
+        .filter(|order| order.order_type == book.order_type())
+        .cloned()
+        .collect();
+    book.insert_batch(&mut requeued);
+    book.amend_batch(amends);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        collar::{CollarBand, PriceBand, PriceCollar},
+        market::SelfTradePrevention,
+        orders::OrderKind,
+        spec::InstrumentSpec,
+    };
+
+    fn add_order(
+        engine: &mut AuctionEngine,
+        order_type: OrderType,
+        rate: Price,
+        quantity: u32,
+    ) -> RegisteredOrder {
+        let order = Order::limit(0, order_type, rate, quantity);
+        match engine.submit(OrderRequest::AddOrder(order)) {
+            SubmitResult::Added(order) => order,
+            result => panic!("Unexpected {:?}", result),
+        }
+    }
+
+    #[test]
+    fn requests_reflected_in_books() {
+        let mut engine = AuctionEngine::new();
+        let bid = add_order(&mut engine, OrderType::Buy, 100, 10);
+        let ask = add_order(&mut engine, OrderType::Sell, 110, 10);
+        let cancelled = add_order(&mut engine, OrderType::Sell, 120, 10);
+        assert_eq!(engine.pending(), 3);
+        engine.flush();
+        assert_eq!(engine.pending(), 0);
+        assert_eq!((engine.bids().len(), engine.asks().len()), (1, 2));
+
+        let mut amend = bid.clone();
+        amend.quantity = 5;
+        assert!(matches!(
+            engine.submit(OrderRequest::ModifyOrder(amend)),
+            SubmitResult::Modified(ModifyResult::Reduced(_))
+        ));
+        let mut amend = ask.clone();
+        amend.rate = 105;
+        assert!(matches!(
+            engine.submit(OrderRequest::ModifyOrder(amend)),
+            SubmitResult::Modified(ModifyResult::Requeued(_))
+        ));
+        assert!(matches!(
+            engine.submit(OrderRequest::CancelOrder(cancelled.id)),
+            SubmitResult::Cancelled(_)
+        ));
+        assert_eq!(
+            engine.submit(OrderRequest::CancelOrder(cancelled.id)),
+            SubmitResult::UnknownOrder
+        );
+        engine.flush();
+        assert_eq!(engine.bids().first().unwrap().quantity, 5);
+        assert_eq!(engine.asks().len(), 1);
+        assert_eq!(engine.asks().first().unwrap().rate, 105);
+    }
+
+    #[test]
+    fn cancel_all_of_account() {
+        let mut engine = AuctionEngine::new();
+        add_order(&mut engine, OrderType::Buy, 100, 10);
+        add_order(&mut engine, OrderType::Sell, 110, 10);
+        engine.flush();
+        let other = match engine.submit(OrderRequest::AddOrder(Order::limit(
+            1,
+            OrderType::Sell,
+            120,
+            10,
+        ))) {
+            SubmitResult::Added(order) => order,
+            result => panic!("Unexpected {:?}", result),
+        };
+        match engine.submit(OrderRequest::CancelAll(0)) {
+            SubmitResult::CancelledAll(cancelled) => assert_eq!(cancelled.len(), 2),
+            result => panic!("Unexpected {:?}", result),
+        }
+        engine.flush();
+        assert!(engine.bids().is_empty());
+        assert_eq!(engine.asks().len(), 1);
+        assert_eq!(engine.asks().first().unwrap().id, other.id);
+        assert_eq!(engine.orders().orders_of(0).count(), 0);
+    }
+
+    #[test]
+    fn auction_clears_filled_orders() {
+        let mut engine = AuctionEngine::new();
+        add_order(&mut engine, OrderType::Buy, 105, 2);
+        let bid = add_order(&mut engine, OrderType::Buy, 100, 10);
+        let ask = add_order(&mut engine, OrderType::Sell, 90, 4);
+        add_order(&mut engine, OrderType::Buy, 80, 10);
+        add_order(&mut engine, OrderType::Sell, 110, 10);
+
+        let result = engine.run_auction().unwrap();
+        assert_eq!(result.traded_volume, 4);
+        assert_eq!(engine.epoch(), 1);
+        assert_eq!(engine.orders().len(), 3);
+        assert_eq!(engine.orders()[bid.id].quantity, 8);
+        assert_eq!(
+            engine.submit(OrderRequest::ModifyOrder(ask)),
+            SubmitResult::Modified(ModifyResult::AlreadyFilled)
+        );
+        assert_eq!(engine.reference_price(), Some(100));
+        assert_eq!(engine.bids().first().unwrap().id, bid.id);
+        assert_eq!(engine.bids().first().unwrap().quantity, 8);
+    }
+
+    #[test]
+    fn auction_applies_self_trade_prevention() {
+        let mut engine = AuctionEngine::with_config(MatchConfig {
+            self_trade_prevention: SelfTradePrevention::DecrementAndCancel,
+            ..Default::default()
+        })
+        .unwrap();
+        let bid = add_order(&mut engine, OrderType::Buy, 100, 10);
+        let ask = add_order(&mut engine, OrderType::Sell, 90, 4);
+        assert_eq!(
+            engine.run_auction().unwrap_err(),
+            MatchErrorKind::OneSidedBook(OrderType::Buy)
+        );
+        assert_eq!(engine.orders().len(), 1);
+        assert!(!engine.orders().contains_key(ask.id));
+        assert_eq!(engine.orders()[bid.id].quantity, 6);
+        assert_eq!(engine.bids().first().unwrap().quantity, 6);
+    }
+
+    #[test]
+    fn iceberg_refresh_loses_priority() {
+        let mut engine = AuctionEngine::new();
+        let iceberg = Order::limit(0, OrderType::Sell, 90, 30).with_peak(10);
+        let iceberg = match engine.submit(OrderRequest::AddOrder(iceberg)) {
+            SubmitResult::Added(order) => order,
+            result => panic!("Unexpected {:?}", result),
+        };
+        assert_eq!((iceberg.displayed, iceberg.hidden()), (10, 20));
+        let plain = add_order(&mut engine, OrderType::Sell, 90, 10);
+        add_order(&mut engine, OrderType::Buy, 100, 12);
+
+        // Displayed quantities go first, then hidden reserve
+        let result = engine.run_auction().unwrap();
+        assert_eq!(result.traded_volume, 12);
+        let filled = |id| {
+            result
+                .trades
+                .iter()
+                .find(|trade| trade.order.id == id)
+                .map(|trade| trade.quantity)
+        };
+        assert_eq!(filled(iceberg.id), Some(10));
+        assert_eq!(filled(plain.id), Some(2));
+
+        let asks: Vec<_> = engine.asks().iter().collect();
+        assert_eq!(asks[0].id, plain.id);
+        assert_eq!(asks[1].id, iceberg.id);
+        assert_eq!((asks[1].displayed, asks[1].quantity), (10, 20));
+        assert_eq!(asks[1].epoch, 1);
+        assert_eq!(&engine.orders()[iceberg.id], asks[1]);
+    }
+
+    #[test]
+    fn stop_orders_triggered_by_traded_rate() {
+        let mut engine = AuctionEngine::new();
+        let submit = |engine: &mut AuctionEngine, order| match engine
+            .submit(OrderRequest::AddOrder(order))
+        {
+            SubmitResult::Added(order) => order,
+            result => panic!("Unexpected {:?}", result),
+        };
+        let stop_loss = submit(&mut engine, Order::stop(0, OrderType::Sell, 96, 5));
+        let stop_limit = submit(&mut engine, Order::stop_limit(0, OrderType::Buy, 94, 99, 5));
+        let far_stop = submit(&mut engine, Order::stop(0, OrderType::Buy, 120, 5));
+        assert_eq!(engine.pending(), 0);
+        assert_eq!(engine.stops(), 3);
+
+        add_order(&mut engine, OrderType::Buy, 95, 10);
+        add_order(&mut engine, OrderType::Sell, 95, 10);
+        add_order(&mut engine, OrderType::Buy, 90, 10);
+        let result = engine.run_auction().unwrap();
+        assert_eq!(result.traded_rate, Some(95));
+        let triggered: Vec<_> = engine.triggered().iter().map(|order| order.id).collect();
+        assert_eq!(triggered, vec![stop_loss.id, stop_limit.id]);
+        assert_eq!(engine.triggered()[0].kind, OrderKind::Market);
+        assert_eq!(engine.triggered()[1].kind, OrderKind::Limit);
+        assert_eq!(engine.triggered()[1].epoch, 1);
+        assert_eq!(engine.stops(), 1);
+        assert_eq!(engine.pending(), 2);
+
+        // Triggered stop loss sells into resting bid
+        let result = engine.run_auction().unwrap();
+        assert_eq!(result.traded_volume, 5);
+        assert!(engine.triggered().is_empty());
+        assert!(engine.orders().contains_key(far_stop.id));
+        assert_eq!(
+            engine.submit(OrderRequest::CancelOrder(far_stop.id)),
+            SubmitResult::Cancelled(far_stop)
+        );
+        assert_eq!(engine.stops(), 0);
+    }
+
+    #[test]
+    fn spec_rejects_requests() {
+        let mut engine = AuctionEngine::with_config(MatchConfig {
+            spec: InstrumentSpec {
+                tick_size: 5,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .unwrap();
+        let order = add_order(&mut engine, OrderType::Buy, 100, 10);
+        let rejection = Rejection::OffTick {
+            rate: 101,
+            tick_size: 5,
+        };
+        assert_eq!(
+            engine.submit(OrderRequest::AddOrder(Order::limit(
+                0,
+                OrderType::Buy,
+                101,
+                10
+            ))),
+            SubmitResult::Rejected(rejection)
+        );
+        let mut amend = order.clone();
+        amend.rate = 101;
+        assert_eq!(
+            engine.submit(OrderRequest::ModifyOrder(amend)),
+            SubmitResult::Rejected(rejection)
+        );
+        assert_eq!(engine.orders().len(), 1);
+        assert_eq!(engine.orders()[order.id].rate, 100);
+        assert_eq!(engine.pending(), 1);
+    }
+
+    #[test]
+    fn time_in_force_expiration() {
+        let mut engine = AuctionEngine::new();
+        let add = |engine: &mut AuctionEngine, order_type, rate, quantity, time_in_force| {
+            let order =
+                Order::limit(0, order_type, rate, quantity).with_time_in_force(time_in_force);
+            engine.submit(OrderRequest::AddOrder(order))
+        };
+        add(
+            &mut engine,
+            OrderType::Buy,
+            100,
+            10,
+            TimeInForce::GoodForAuction,
+        );
+        add(&mut engine, OrderType::Buy, 99, 10, TimeInForce::FillOrKill);
+        add(
+            &mut engine,
+            OrderType::Buy,
+            98,
+            10,
+            TimeInForce::GoodTillEpoch(1),
+        );
+        add(
+            &mut engine,
+            OrderType::Buy,
+            97,
+            10,
+            TimeInForce::GoodTillCancel,
+        );
+        add(
+            &mut engine,
+            OrderType::Sell,
+            90,
+            15,
+            TimeInForce::GoodTillCancel,
+        );
+
+        // Fill-or-kill order would only get 5 and is skipped
+        let result = engine.run_auction().unwrap();
+        assert_eq!(result.traded_volume, 15);
+        assert_eq!(result.skipped.len(), 1);
+        let mut expired: Vec<_> = engine.expired().iter().map(|order| order.rate).collect();
+        expired.sort_unstable();
+        assert_eq!(expired, vec![99]);
+        assert_eq!(engine.orders().len(), 2);
+        assert_eq!(engine.bids().len(), 2);
+
+        assert!(engine.run_auction().is_err());
+        assert_eq!(engine.expired().len(), 1);
+        assert_eq!(engine.expired()[0].rate, 98);
+        assert_eq!(engine.bids().len(), 1);
+        assert_eq!(engine.orders().len(), 1);
+        assert!(matches!(
+            add(
+                &mut engine,
+                OrderType::Buy,
+                96,
+                10,
+                TimeInForce::GoodTillEpoch(1)
+            ),
+            SubmitResult::Expired(_)
+        ));
+    }
+
+    #[test]
+    fn auction_without_trade_keeps_books() {
+        let mut engine = AuctionEngine::new();
+        assert_eq!(engine.run_auction().unwrap_err(), MatchErrorKind::EmptyBook);
+        add_order(&mut engine, OrderType::Buy, 90, 10);
+        add_order(&mut engine, OrderType::Sell, 100, 10);
+        assert!(matches!(
+            engine.run_auction(),
+            Err(MatchErrorKind::NoCross { .. })
+        ));
+        assert_eq!(engine.epoch(), 2);
+        assert_eq!((engine.bids().len(), engine.asks().len()), (1, 1));
+        assert_eq!(engine.orders().len(), 2);
+    }
+
+    #[test]
+    fn volatility_interruption_extends_auction() {
+        let mut engine = AuctionEngine::with_config(MatchConfig {
+            collar: PriceCollar {
+                dynamic_tolerance: Some(500),
+                on_breach: BreachAction::Extend(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .unwrap();
+        add_order(&mut engine, OrderType::Buy, 100, 10);
+        add_order(&mut engine, OrderType::Sell, 100, 10);
+        engine.run_auction().unwrap();
+
+        add_order(&mut engine, OrderType::Buy, 120, 10);
+        add_order(&mut engine, OrderType::Sell, 110, 10);
+        match engine.run_auction() {
+            Err(MatchErrorKind::VolatilityInterruption(interruption)) => {
+                assert_eq!(interruption.rate, 110);
+                assert_eq!(interruption.band, CollarBand::Dynamic);
+                assert_eq!((interruption.lowest, interruption.highest), (95, 105));
+            }
+            result => panic!("Unexpected {:?}", result),
+        }
+        let extended = TradingState::Extended { until: 4 };
+        assert_eq!(engine.state(), extended);
+        assert_eq!((engine.bids().len(), engine.asks().len()), (1, 1));
+
+        // Books keep collecting orders during extension
+        add_order(&mut engine, OrderType::Sell, 104, 10);
+        for _ in 0..2 {
+            assert_eq!(
+                engine.run_auction().unwrap_err(),
+                MatchErrorKind::Halted(extended)
+            );
+        }
+        let result = engine.run_auction().unwrap();
+        assert_eq!(result.traded_rate, Some(104));
+        assert_eq!(engine.state(), TradingState::Open);
+    }
+
+    #[test]
+    fn extended_auction_keeps_orders_for_next_auction() {
+        let mut engine = AuctionEngine::with_config(MatchConfig {
+            collar: PriceCollar {
+                dynamic_tolerance: Some(500),
+                on_breach: BreachAction::Extend(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .unwrap();
+        add_order(&mut engine, OrderType::Buy, 100, 10);
+        add_order(&mut engine, OrderType::Sell, 100, 10);
+        engine.run_auction().unwrap();
+
+        let add = |engine: &mut AuctionEngine, order_type, rate, time_in_force| {
+            let order = Order::limit(0, order_type, rate, 10).with_time_in_force(time_in_force);
+            match engine.submit(OrderRequest::AddOrder(order)) {
+                SubmitResult::Added(order) => order,
+                result => panic!("Unexpected {:?}", result),
+            }
+        };
+        let bid = add(
+            &mut engine,
+            OrderType::Buy,
+            120,
+            TimeInForce::GoodForAuction,
+        );
+        let ask = add(&mut engine, OrderType::Sell, 110, TimeInForce::FillOrKill);
+        let last = add(
+            &mut engine,
+            OrderType::Buy,
+            90,
+            TimeInForce::GoodTillEpoch(2),
+        );
+        assert!(matches!(
+            engine.run_auction(),
+            Err(MatchErrorKind::VolatilityInterruption(_))
+        ));
+        assert!(engine.expired().is_empty());
+        assert_eq!((engine.bids().len(), engine.asks().len()), (2, 1));
+
+        // Only orders past their last epoch expire while auction is extended
+        let extended = TradingState::Extended { until: 3 };
+        assert_eq!(
+            engine.run_auction().unwrap_err(),
+            MatchErrorKind::Halted(extended)
+        );
+        assert_eq!(engine.expired(), &[last]);
+        assert_eq!((engine.bids().len(), engine.asks().len()), (1, 1));
+
+        add_order(&mut engine, OrderType::Sell, 104, 10);
+        let result = engine.run_auction().unwrap();
+        assert_eq!(result.traded_rate, Some(104));
+        assert!(result.trades.iter().any(|trade| trade.order.id == bid.id));
+        assert_eq!(engine.expired(), &[ask]);
+        assert!(engine.bids().is_empty());
+    }
+
+    #[test]
+    fn volatility_interruption_suspends_auction() {
+        let mut engine = AuctionEngine::with_config(MatchConfig {
+            collar: PriceCollar {
+                static_band: Some(PriceBand {
+                    reference: 100,
+                    tolerance: 1_000,
+                }),
+                on_breach: BreachAction::Suspend,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .unwrap();
+        add_order(&mut engine, OrderType::Buy, 115, 10);
+        add_order(&mut engine, OrderType::Sell, 115, 10);
+        assert!(matches!(
+            engine.run_auction(),
+            Err(MatchErrorKind::VolatilityInterruption(_))
+        ));
+        for _ in 0..3 {
+            assert_eq!(
+                engine.run_auction().unwrap_err(),
+                MatchErrorKind::Halted(TradingState::Suspended)
+            );
+        }
+
+        engine.resume();
+        add_order(&mut engine, OrderType::Sell, 110, 10);
+        let result = engine.run_auction().unwrap();
+        assert_eq!(result.traded_rate, Some(110));
+        assert_eq!(engine.epoch(), 5);
+    }
+}
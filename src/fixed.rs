@@ -0,0 +1,198 @@
+//! Fixed-point prices and quantities.
+//! Values are integers counting the smallest increment of the instrument,
+//! its decimal scale tells how many of their digits are fractional.
+//! Matching works on integer values only, scale matters when orders enter
+//! and results leave the venue.
+//!
+//! `i32` prices with `u32` quantities are the default instantiation,
+//! `i64` and `u64` fit instruments with fine ticks or large notionals.
+
+use std::{
+    convert::TryFrom,
+    fmt::{Debug, Display},
+    hash::Hash,
+    ops::{Add, AddAssign, Sub, SubAssign},
+};
+
+pub trait PriceValue:
+    Copy + Ord + Hash + Default + Debug + Display + Send + Sync + 'static
+{
+    /// Lowest price, rate of market sell orders
+    const MIN: Self;
+    /// Highest price, rate of market buy orders
+    const MAX: Self;
+
+    fn to_raw(self) -> i64;
+
+    /// None if `raw` is out of range of the type
+    fn from_raw(raw: i64) -> Option<Self>;
+
+    /// Price halfway from `self` to `other`, rounded towards `self`
+    #[inline]
+    fn midpoint(self, other: Self) -> Self {
+        let (from, to) = (self.to_raw() as i128, other.to_raw() as i128);
+        let raw = from + (to - from) / 2;
+        Self::from_raw(raw as i64).expect("midpoint lies between prices")
+    }
+}
+
+pub trait QuantityValue:
+    Copy
+    + Ord
+    + Hash
+    + Default
+    + Debug
+    + Display
+    + Send
+    + Sync
+    + 'static
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + AddAssign
+    + SubAssign
+{
+    const ZERO: Self;
+    const ONE: Self;
+    const MAX: Self;
+
+    fn to_raw(self) -> u64;
+
+    /// None if `raw` is out of range of the type
+    fn from_raw(raw: u64) -> Option<Self>;
+}
+
+macro_rules! impl_price {
+    ($($t:ty),*) => {$(
+        impl PriceValue for $t {
+            const MIN: Self = <$t>::MIN;
+            const MAX: Self = <$t>::MAX;
+
+            #[inline]
+            fn to_raw(self) -> i64 {
+                self as i64
+            }
+
+            #[inline]
+            fn from_raw(raw: i64) -> Option<Self> {
+                <$t>::try_from(raw).ok()
+            }
+        }
+    )*};
+}
+
+macro_rules! impl_quantity {
+    ($($t:ty),*) => {$(
+        impl QuantityValue for $t {
+            const ZERO: Self = 0;
+            const ONE: Self = 1;
+            const MAX: Self = <$t>::MAX;
+
+            #[inline]
+            fn to_raw(self) -> u64 {
+                self as u64
+            }
+
+            #[inline]
+            fn from_raw(raw: u64) -> Option<Self> {
+                <$t>::try_from(raw).ok()
+            }
+        }
+    )*};
+}
+
+impl_price!(i32, i64);
+impl_quantity!(u32, u64);
+
+/// Value of `quantity` traded at `rate`, scale is the sum of price and quantity scales
+#[inline]
+pub fn notional<P: PriceValue, Q: QuantityValue>(rate: P, quantity: Q) -> i128 {
+    // i64 by u64 product always fits i128
+    rate.to_raw() as i128 * quantity.to_raw() as i128
+}
+
+/// Decimal text of fixed-point `value` with `scale` fractional digits
+pub fn format_fixed(value: i128, scale: u32) -> String {
+    let digits = value.unsigned_abs().to_string();
+    let scale = scale as usize;
+    let digits = if digits.len() <= scale {
+        format!("{}{}", "0".repeat(scale + 1 - digits.len()), digits)
+    } else {
+        digits
+    };
+    let (whole, fraction) = digits.split_at(digits.len() - scale);
+    let sign = if value < 0 { "-" } else { "" };
+    if fraction.is_empty() {
+        format!("{}{}", sign, whole)
+    } else {
+        format!("{}{}.{}", sign, whole, fraction)
+    }
+}
+
+/// Fixed-point value of decimal `text` with `scale` fractional digits,
+/// None if text is malformed, has more fractional digits or overflows
+pub fn parse_fixed(text: &str, scale: u32) -> Option<i128> {
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(text) => (true, text),
+        None => (false, text),
+    };
+    let (whole, fraction) = match text.find('.') {
+        Some(idx) => (&text[..idx], &text[idx + 1..]),
+        None => (text, ""),
+    };
+    let all_digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+    if whole.is_empty()
+        || fraction.len() > scale as usize
+        || !all_digits(whole)
+        || !all_digits(fraction)
+    {
+        return None;
+    }
+    let mut value: i128 = 0;
+    for byte in whole.bytes().chain(fraction.bytes()) {
+        value = value.checked_mul(10)?.checked_add((byte - b'0') as i128)?;
+    }
+    let padding = scale - fraction.len() as u32;
+    let value = value.checked_mul(10i128.checked_pow(padding)?)?;
+    Some(if negative { -value } else { value })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn midpoint() {
+        assert_eq!(PriceValue::midpoint(90, 100), 95);
+        assert_eq!(PriceValue::midpoint(90, 99), 94);
+        assert_eq!(PriceValue::midpoint(i32::MIN, i32::MAX), -1);
+        assert_eq!(PriceValue::midpoint(i64::MIN, i64::MAX), -1);
+        assert_eq!(PriceValue::midpoint(-5i64, -2), -4);
+    }
+
+    #[test]
+    fn raw_conversion() {
+        assert_eq!(<i32 as PriceValue>::from_raw(i64::MAX), None);
+        assert_eq!(<i32 as PriceValue>::from_raw(-7), Some(-7));
+        assert_eq!(<u32 as QuantityValue>::from_raw(u64::MAX), None);
+        assert_eq!(<u64 as QuantityValue>::from_raw(u64::MAX), Some(u64::MAX));
+        assert_eq!(
+            notional(i64::MIN, u64::MAX),
+            i64::MIN as i128 * u64::MAX as i128
+        );
+    }
+
+    #[test]
+    fn decimal_text() {
+        assert_eq!(format_fixed(12345, 2), "123.45");
+        assert_eq!(format_fixed(-5, 3), "-0.005");
+        assert_eq!(format_fixed(42, 0), "42");
+        assert_eq!(parse_fixed("123.45", 2), Some(12345));
+        assert_eq!(parse_fixed("-0.005", 3), Some(-5));
+        assert_eq!(parse_fixed("1.5", 8), Some(150_000_000));
+        assert_eq!(parse_fixed("42", 0), Some(42));
+        assert_eq!(parse_fixed("1.234", 2), None);
+        assert_eq!(parse_fixed(".5", 2), None);
+        assert_eq!(parse_fixed("1e3", 2), None);
+        assert_eq!(parse_fixed("1.", 2), Some(100));
+    }
+}
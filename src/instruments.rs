@@ -0,0 +1,260 @@
+//! Registry of listed instruments, each one has its own order registry,
+//! sorted books and auction engine. Auctions of all instruments run in parallel.
+//! Order ids come from one key space and are unique over all instruments.
+//! Instruments share price and quantity types, decimal scale is set by spec of each one.
+
+use std::collections::HashMap;
+
+use rayon::prelude::*;
+
+use crate::{
+    book::OrderBookSide,
+    engine::{AuctionEngine, AuctionResult, OrderRequest, SubmitResult},
+    fixed::{PriceValue, QuantityValue},
+    market::{IndicativeMatch, MatchConfig, MatchErrorKind},
+    orders::{AccountId, InstrumentId, OrderIds, Price, Quantity, RegisteredOrder},
+    sorted_vec_orders::SortedOrders,
+    spec::SpecError,
+};
+
+/// Reason instrument could not be listed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListingError<P = Price, Q = Quantity> {
+    AlreadyListed,
+    InvalidSpec(SpecError<P, Q>),
+}
+
+/// Instruments keep their books in storage `B`
+pub struct InstrumentRegistry<P = Price, Q = Quantity, B = SortedOrders<P, Q>> {
+    engines: HashMap<InstrumentId, AuctionEngine<P, Q, B>>,
+    /// Key space shared by engines of all instruments
+    ids: OrderIds,
+}
+
+impl<P, Q, B> Default for InstrumentRegistry<P, Q, B> {
+    fn default() -> Self {
+        Self {
+            engines: HashMap::new(),
+            ids: OrderIds::default(),
+        }
+    }
+}
+
+impl<P: PriceValue, Q: QuantityValue> InstrumentRegistry<P, Q> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<P: PriceValue, Q: QuantityValue, B: OrderBookSide<P, Q>> InstrumentRegistry<P, Q, B> {
+    /// List instrument with its auction settings, spec of `config` has to be valid
+    pub fn list(
+        &mut self,
+        instrument: InstrumentId,
+        config: MatchConfig<P, Q>,
+    ) -> Result<(), ListingError<P, Q>> {
+        if self.engines.contains_key(&instrument) {
+            return Err(ListingError::AlreadyListed);
+        }
+        let engine = AuctionEngine::with_storage(config)
+            .map_err(ListingError::InvalidSpec)?
+            .with_order_ids(self.ids.clone());
+        self.engines.insert(instrument, engine);
+        Ok(())
+    }
+
+    /// Remove instrument together with its open orders
+    pub fn delist(&mut self, instrument: InstrumentId) -> Option<AuctionEngine<P, Q, B>> {
+        self.engines.remove(&instrument)
+    }
+
+    #[inline]
+    pub fn engine(&self, instrument: InstrumentId) -> Option<&AuctionEngine<P, Q, B>> {
+        self.engines.get(&instrument)
+    }
+
+    pub fn instruments(&self) -> impl Iterator<Item = InstrumentId> + '_ {
+        self.engines.keys().copied()
+    }
+
+    /// Number of live orders over all instruments
+    pub fn open_orders(&self) -> usize {
+        self.engines
+            .values()
+            .map(|engine| engine.orders().len())
+            .sum()
+    }
+
+    /// Number of new orders waiting for flush over all instruments
+    pub fn pending(&self) -> usize {
+        self.engines.values().map(AuctionEngine::pending).sum()
+    }
+
+    /// Route request to the instrument's engine, orders of other instruments are unknown to it
+    /// and `CancelAll` only cancels orders of the instrument, see `cancel_all`
+    pub fn submit(
+        &mut self,
+        instrument: InstrumentId,
+        request: OrderRequest<P, Q>,
+    ) -> SubmitResult<P, Q> {
+        let routed = match &request {
+            OrderRequest::AddOrder(order) => order.instrument == instrument,
+            OrderRequest::ModifyOrder(order) => order.instrument == instrument,
+            OrderRequest::CancelOrder(_) | OrderRequest::CancelAll(_) => true,
+        };
+        match self.engines.get_mut(&instrument) {
+            Some(engine) if routed => engine.submit(request),
+            _ => SubmitResult::UnknownInstrument,
+        }
+    }
+
+    /// Cancel all orders of the account on every instrument
+    pub fn cancel_all(&mut self, account: AccountId) -> Vec<RegisteredOrder<P, Q>> {
+        let mut cancelled = Vec::new();
+        for engine in self.engines.values_mut() {
+            if let SubmitResult::CancelledAll(orders) =
+                engine.submit(OrderRequest::CancelAll(account))
+            {
+                cancelled.extend(orders);
+            }
+        }
+        cancelled
+    }
+
+    /// Apply pending batches of all instruments
+    pub fn flush(&mut self) {
+        self.engines
+            .par_iter_mut()
+            .for_each(|(_, engine)| engine.flush());
+    }
+
+    /// Indicative auction outcome of every instrument on flushed books
+    pub fn indicative(
+        &self,
+    ) -> HashMap<InstrumentId, Result<IndicativeMatch<P>, MatchErrorKind<P>>> {
+        self.engines
+            .par_iter()
+            .map(|(instrument, engine)| (*instrument, engine.indicative()))
+            .collect()
+    }
+
+    /// Run auction of every instrument in parallel
+    pub fn run_auctions(&mut self) -> HashMap<InstrumentId, AuctionResult<P, Q, B>> {
+        self.engines
+            .par_iter_mut()
+            .map(|(instrument, engine)| (*instrument, engine.run_auction()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        orders::{Order, OrderType},
+        spec::InstrumentSpec,
+    };
+
+    fn add_order(
+        registry: &mut InstrumentRegistry,
+        instrument: InstrumentId,
+        order_type: OrderType,
+        rate: i32,
+        quantity: u32,
+    ) -> SubmitResult {
+        let order = Order::limit(0, order_type, rate, quantity).on_instrument(instrument);
+        registry.submit(instrument, OrderRequest::AddOrder(order))
+    }
+
+    #[test]
+    fn instruments_have_separate_books() {
+        let mut registry = InstrumentRegistry::new();
+        assert_eq!(registry.list(1, MatchConfig::default()), Ok(()));
+        assert_eq!(registry.list(2, MatchConfig::default()), Ok(()));
+        assert_eq!(
+            registry.list(2, MatchConfig::default()),
+            Err(ListingError::AlreadyListed)
+        );
+        let config = MatchConfig {
+            spec: InstrumentSpec {
+                lot_size: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            registry.list(3, config),
+            Err(ListingError::InvalidSpec(SpecError::ZeroLotSize))
+        );
+
+        add_order(&mut registry, 1, OrderType::Buy, 100, 10);
+        add_order(&mut registry, 1, OrderType::Sell, 90, 10);
+        add_order(&mut registry, 2, OrderType::Buy, 100, 10);
+        add_order(&mut registry, 2, OrderType::Sell, 110, 10);
+        assert_eq!(
+            add_order(&mut registry, 3, OrderType::Buy, 100, 10),
+            SubmitResult::UnknownInstrument
+        );
+        let order = Order::limit(0, OrderType::Buy, 100, 10).on_instrument(2);
+        assert_eq!(
+            registry.submit(1, OrderRequest::AddOrder(order)),
+            SubmitResult::UnknownInstrument
+        );
+        assert_eq!(registry.pending(), 4);
+
+        let results = registry.run_auctions();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[&1].as_ref().unwrap().traded_volume, 10);
+        assert!(matches!(results[&2], Err(MatchErrorKind::NoCross { .. })));
+        assert_eq!(registry.open_orders(), 2);
+        assert_eq!(registry.engine(2).unwrap().bids().len(), 1);
+        assert!(registry.engine(1).unwrap().bids().is_empty());
+    }
+
+    #[test]
+    fn order_ids_are_unique_over_instruments() {
+        let mut registry = InstrumentRegistry::new();
+        registry.list(1, MatchConfig::default()).unwrap();
+        registry.list(2, MatchConfig::default()).unwrap();
+        let submit = |registry: &mut InstrumentRegistry, instrument, account| {
+            let order = Order::limit(account, OrderType::Buy, 100, 10).on_instrument(instrument);
+            match registry.submit(instrument, OrderRequest::AddOrder(order)) {
+                SubmitResult::Added(order) => order,
+                result => panic!("Unexpected {:?}", result),
+            }
+        };
+        let first = submit(&mut registry, 1, 7);
+        let other = submit(&mut registry, 2, 8);
+        assert_ne!(first.id, other.id);
+        // Cancel routed to the wrong instrument does not reach orders of another account
+        assert_eq!(
+            registry.submit(2, OrderRequest::CancelOrder(first.id)),
+            SubmitResult::UnknownOrder
+        );
+        assert_eq!(registry.open_orders(), 2);
+        assert!(matches!(
+            registry.submit(1, OrderRequest::CancelOrder(first.id)),
+            SubmitResult::Cancelled(_)
+        ));
+    }
+
+    #[test]
+    fn cancel_all_reaches_every_instrument() {
+        let mut registry = InstrumentRegistry::new();
+        for instrument in 1..=3 {
+            registry.list(instrument, MatchConfig::default()).unwrap();
+            add_order(&mut registry, instrument, OrderType::Buy, 100, 10);
+        }
+        let order = Order::limit(1, OrderType::Sell, 110, 10).on_instrument(2);
+        registry.submit(2, OrderRequest::AddOrder(order));
+
+        let cancelled = registry.cancel_all(0);
+        assert_eq!(cancelled.len(), 3);
+        assert!(cancelled.iter().all(|order| order.account == 0));
+        assert_eq!(registry.open_orders(), 1);
+        registry.flush();
+        assert!(registry
+            .instruments()
+            .all(|instrument| { registry.engine(instrument).unwrap().bids().is_empty() }));
+    }
+}
@@ -0,0 +1,270 @@
+//! Book side aggregated by price, an alternative to `SortedOrders` for books
+//! with far more orders than distinct prices:
+//! -- Limit orders queue per price level in `BTreeMap`, market orders in a level ahead of them
+//! -- Each level caches total quantity, crossing volumes are summed per level
+//! -- Batch insert finds level of each order instead of sorting the whole book
+//! -- Cancel and amend find level and slot of the order by id in O(1)
+
+use crate::{
+    book::{LevelVolume, OrderBookSide, OrderQueue, QueueIter},
+    fixed::{PriceValue, QuantityValue},
+    orders::{OrderId, OrderType, Price, Quantity, RegisteredOrder},
+    spec::{InstrumentSpec, SpecError},
+};
+use slotmap::SparseSecondaryMap;
+use std::collections::{btree_map, BTreeMap};
+
+/// Level an order is queued at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LevelKey<P> {
+    Market,
+    Limit(P),
+}
+
+#[derive(Debug)]
+pub struct LevelOrders<P = Price, Q = Quantity> {
+    order_type: OrderType,
+    market: OrderQueue<P, Q>,
+    levels: BTreeMap<P, OrderQueue<P, Q>>,
+    /// Level and slot in its queue of every order in the book
+    index: SparseSecondaryMap<OrderId, (LevelKey<P>, usize)>,
+}
+
+/// Orders of the book from the best one
+pub struct Iter<'a, P, Q> {
+    level: QueueIter<'a, P, Q>,
+    levels: btree_map::Values<'a, P, OrderQueue<P, Q>>,
+    /// Bids walk levels from the highest price
+    descending: bool,
+}
+
+impl<P: PriceValue, Q: QuantityValue> LevelOrders<P, Q> {
+    pub fn new(order_type: OrderType) -> Self {
+        Self {
+            order_type,
+            market: Default::default(),
+            levels: BTreeMap::new(),
+            index: SparseSecondaryMap::new(),
+        }
+    }
+
+    /// Number of price levels of limit orders
+    #[inline]
+    pub fn levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    #[inline]
+    fn key(order: &RegisteredOrder<P, Q>) -> LevelKey<P> {
+        if order.is_market() {
+            LevelKey::Market
+        } else {
+            LevelKey::Limit(order.rate)
+        }
+    }
+
+    fn queue_mut(&mut self, key: LevelKey<P>) -> Option<&mut OrderQueue<P, Q>> {
+        match key {
+            LevelKey::Market => Some(&mut self.market),
+            LevelKey::Limit(rate) => self.levels.get_mut(&rate),
+        }
+    }
+
+    fn best_key(&self) -> Option<LevelKey<P>> {
+        if !self.market.is_empty() {
+            return Some(LevelKey::Market);
+        }
+        let best = match self.order_type {
+            OrderType::Buy => self.levels.keys().next_back(),
+            OrderType::Sell => self.levels.keys().next(),
+        };
+        best.map(|rate| LevelKey::Limit(*rate))
+    }
+
+    /// Drop level left without orders
+    fn prune(&mut self, key: LevelKey<P>) {
+        if let LevelKey::Limit(rate) = key {
+            if self.levels.get(&rate).is_some_and(OrderQueue::is_empty) {
+                self.levels.remove(&rate);
+            }
+        }
+    }
+}
+
+impl<P: PriceValue, Q: QuantityValue> OrderBookSide<P, Q> for LevelOrders<P, Q> {
+    type Iter<'a> = Iter<'a, P, Q>;
+
+    #[inline]
+    fn new(order_type: OrderType, _spec: &InstrumentSpec<P, Q>) -> Result<Self, SpecError<P, Q>> {
+        Ok(LevelOrders::new(order_type))
+    }
+
+    #[inline]
+    fn order_type(&self) -> OrderType {
+        self.order_type
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    #[inline]
+    fn iter(&self) -> Self::Iter<'_> {
+        Iter {
+            level: self.market.iter(),
+            levels: self.levels.values(),
+            descending: self.order_type == OrderType::Buy,
+        }
+    }
+
+    fn insert_batch(&mut self, orders: &mut Vec<RegisteredOrder<P, Q>>) {
+        for order in orders.drain(..) {
+            let (id, key) = (order.id, Self::key(&order));
+            let slot = match key {
+                LevelKey::Market => self.market.insert(order),
+                LevelKey::Limit(rate) => self.levels.entry(rate).or_default().insert(order),
+            };
+            self.index.insert(id, (key, slot));
+        }
+    }
+
+    fn remove_batch(&mut self, ids: &SparseSecondaryMap<OrderId, ()>) {
+        for id in ids.keys() {
+            if let Some((key, slot)) = self.index.remove(id) {
+                if let Some(queue) = self.queue_mut(key) {
+                    queue.remove(slot);
+                }
+                self.prune(key);
+            }
+        }
+    }
+
+    fn amend_batch(&mut self, amends: &SparseSecondaryMap<OrderId, Q>) {
+        for (id, quantity) in amends.iter() {
+            let (key, slot) = match self.index.get(id) {
+                Some(position) => *position,
+                None => continue,
+            };
+            if let Some(queue) = self.queue_mut(key) {
+                queue.amend(slot, *quantity);
+            }
+        }
+    }
+
+    fn drain_prefix(&mut self, count: usize) -> Vec<RegisteredOrder<P, Q>> {
+        let mut drained = Vec::with_capacity(count);
+        while drained.len() < count {
+            let key = match self.best_key() {
+                Some(key) => key,
+                None => break,
+            };
+            let Self {
+                market,
+                levels,
+                index,
+                ..
+            } = self;
+            let queue = match key {
+                LevelKey::Market => market,
+                LevelKey::Limit(rate) => levels.get_mut(&rate).expect("best level"),
+            };
+            while drained.len() < count {
+                match queue.pop_front() {
+                    Some(order) => {
+                        index.remove(order.id);
+                        drained.push(order);
+                    }
+                    None => break,
+                }
+            }
+            self.prune(key);
+        }
+        drained
+    }
+
+    fn prepend_batch(&mut self, orders: Vec<RegisteredOrder<P, Q>>) {
+        for order in orders.into_iter().rev() {
+            let (id, key) = (order.id, Self::key(&order));
+            let slot = match key {
+                LevelKey::Market => self.market.push_front(order),
+                LevelKey::Limit(rate) => self.levels.entry(rate).or_default().push_front(order),
+            };
+            self.index.insert(id, (key, slot));
+        }
+    }
+
+    fn volumes_while(&self, crosses: impl Fn(P) -> bool) -> Vec<LevelVolume<P>> {
+        let market = self.market.volume(true);
+        let limits = self.levels.values().filter_map(|queue| queue.volume(false));
+        let limits: Box<dyn Iterator<Item = LevelVolume<P>>> = match self.order_type {
+            OrderType::Buy => Box::new(limits.rev()),
+            OrderType::Sell => Box::new(limits),
+        };
+        market
+            .into_iter()
+            .chain(limits)
+            .take_while(|level| crosses(level.rate))
+            .collect()
+    }
+}
+
+impl<'a, P, Q> Iterator for Iter<'a, P, Q> {
+    type Item = &'a RegisteredOrder<P, Q>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(order) = self.level.next() {
+                return Some(order);
+            }
+            let queue = if self.descending {
+                self.levels.next_back()
+            } else {
+                self.levels.next()
+            }?;
+            self.level = queue.iter();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        book::DepthLevel,
+        orders::{Order, RegisteredOrders},
+    };
+
+    #[test]
+    fn level_orders_depth() {
+        let mut registered = RegisteredOrders::default();
+        let mut book = LevelOrders::new(OrderType::Buy);
+        let mut samples = vec![
+            Order::limit(0, OrderType::Buy, 100, 10),
+            Order::limit(0, OrderType::Buy, 101, 50).with_peak(5),
+            Order::limit(0, OrderType::Buy, 101, 10),
+            Order::market(0, OrderType::Buy, 10),
+        ]
+        .into_iter()
+        .map(|order| registered.add_get_order(order, 0))
+        .collect();
+        book.insert_batch(&mut samples);
+        assert_eq!(book.levels(), 2);
+        assert!(OrderBookSide::first(&book).unwrap().is_market());
+        assert_eq!(
+            book.depth(1),
+            vec![DepthLevel {
+                rate: 101,
+                quantity: 15,
+                orders: 2
+            }]
+        );
+        assert_eq!(
+            book.volumes_while(|rate| rate >= 101)
+                .iter()
+                .map(|level| (level.quantity, level.market))
+                .collect::<Vec<_>>(),
+            vec![(10, true), (60, false)]
+        );
+    }
+}
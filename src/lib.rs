@@ -1,4 +1,14 @@
+pub mod fixed;
+pub mod book;
 pub mod orders;
 pub mod sorted_vec_orders;
+pub mod level_orders;
+pub mod bucket_orders;
 pub mod market;
-//pub mod market_ndarray;
+pub mod pricing;
+pub mod allocation;
+pub mod collar;
+pub mod mcafee;
+pub mod engine;
+pub mod instruments;
+pub mod spec;
@@ -1,5 +1,8 @@
+//! Random market simulation driving `hft::instruments::InstrumentRegistry`
+//! with `INSTRUMENTS` listed instruments.
+//!
 //! Processing pipeline:
-//! -- Create Buy/Sell order with id or Cancel order request
+//! -- Create Buy/Sell order with id, Modify or Cancel order request
 //! -- Register order
 //! -- Route orders to Buy / Sell collection
 //!
@@ -20,22 +23,18 @@ use std::{
     time::{Duration, Instant},
 };
 
-use hft::sorted_vec_orders::SortedOrders;
 use hft::{
-    market::market_match,
-    orders::{Order, OrderId, OrderType, RegisteredOrder, RegisteredOrders},
+    book::OrderBookSide,
+    bucket_orders::BucketOrders,
+    engine::{OrderRequest, SubmitResult},
+    instruments::InstrumentRegistry,
+    market::MatchConfig,
+    orders::{ModifyResult, Order, OrderType, Price, Quantity},
+    spec::InstrumentSpec,
 };
 use nanorand::{WyRand, RNG};
-use slotmap::SparseSecondaryMap;
 use statistical::{mean, standard_deviation};
 
-#[derive(Debug, Clone)]
-pub enum OrderRequest {
-    CancelOrder(OrderId),
-    ModifyOrder(RegisteredOrder),
-    AddOrder(Order, u16),
-}
-
 #[derive(Default)]
 pub struct Stats {
     processing: Vec<Duration>,
@@ -49,143 +48,195 @@ const BATCH_SIZE: usize = 10_000;
 const ORDERS: usize = 10_000_000;
 const EPOCH_NS: u128 = 100_000_000;
 const CIRCULATION: usize = 250_000;
+const INSTRUMENTS: u32 = 4;
+/// Range of random order prices with spread around them
+const MIN_PRICE: Price = 80_000;
+const MAX_PRICE: Price = 120_000;
 
 fn main() {
     let mut stats = Stats::default();
-    let mut orders = RegisteredOrders::default();
-    let mut bids = SortedOrders::new(OrderType::Buy);
-    let mut asks = SortedOrders::new(OrderType::Sell);
-    let mut buy_batch: Vec<RegisteredOrder> = Vec::with_capacity(BATCH_SIZE);
-    let mut sell_batch: Vec<RegisteredOrder> = Vec::with_capacity(BATCH_SIZE);
-    let mut cancel_ids = SparseSecondaryMap::new();
+    let mut registry: InstrumentRegistry<Price, Quantity, BucketOrders> = Default::default();
+    for instrument in 0..INSTRUMENTS {
+        let spec = InstrumentSpec {
+            min_price: MIN_PRICE,
+            max_price: MAX_PRICE,
+            ..Default::default()
+        };
+        registry
+            .list(
+                instrument,
+                MatchConfig {
+                    spec,
+                    ..Default::default()
+                },
+            )
+            .expect("simulated instrument spec is valid");
+    }
+    let mut epoch = 0;
+    let mut live_bids = Vec::new();
+    let mut live_asks = Vec::new();
 
     println!("Pregenerating input {} orders", ORDERS);
     let mut rng = WyRand::new();
     let input: Vec<_> = (0..ORDERS)
-        .map(|_| Order::random(&mut rng, 850_00, 1_150_00, 100_00))
+        .map(|_| {
+            let instrument = rng.generate_range(0, INSTRUMENTS);
+            Order::random(&mut rng, 85_000, 115_000, 10_000).on_instrument(instrument)
+        })
         .enumerate()
         .collect();
 
     println!("Starting market emulation");
     let total = std::time::Instant::now();
     let mut period = std::time::Instant::now();
-    let mut epoch = 0;
     let mut cancel_is_bid = true;
     let mut cancel_count = 0;
+    let mut modify_count = 0;
     let mut add_count = 0;
 
     for (i, order) in input {
-        let cancel = if orders.len() < CIRCULATION {
-            false
+        // After CIRCULATION boundary reached 3/8 chance to cancel and 1/8 to modify orders
+        let roll = if registry.open_orders() < CIRCULATION {
+            u8::MAX
         } else {
-            // 50% chance to cancel orders after CIRCULATION boundary reached
-            rng.generate::<u8>() < 128
+            rng.generate::<u8>()
         };
 
         // 1. Generate request
-        let request = if cancel {
-            if let Some(order) = if cancel_is_bid {
-                bids.pop()
-            } else {
-                asks.pop()
-            } {
-                cancel_is_bid = !cancel_is_bid;
-                cancel_count += 1;
-                OrderRequest::CancelOrder(order.id)
+        let (instrument, request) = if roll < 128 {
+            let live = if cancel_is_bid {
+                &mut live_bids
             } else {
+                &mut live_asks
+            };
+            if live.is_empty() {
                 continue;
             }
+            cancel_is_bid = !cancel_is_bid;
+            let idx = rng.generate::<u64>() as usize % live.len();
+            if roll < 96 {
+                let (instrument, id) = live.swap_remove(idx);
+                (instrument, OrderRequest::CancelOrder(id))
+            } else {
+                let (instrument, id) = live[idx];
+                let orders = registry.engine(instrument).unwrap().orders();
+                // Order was already filled in one of previous auctions
+                if !orders.contains_key(id) {
+                    live.swap_remove(idx);
+                    continue;
+                }
+                let mut amend = orders[id].clone();
+                // Reduce quantity keeping priority or step price towards the other side
+                if amend.is_market() || rng.generate::<bool>() {
+                    amend.set_quantity((amend.quantity / 2).max(1));
+                } else {
+                    amend.rate += match amend.order_type {
+                        OrderType::Buy => 1,
+                        OrderType::Sell => -1,
+                    };
+                }
+                (instrument, OrderRequest::ModifyOrder(amend))
+            }
         } else {
-            add_count += 1;
-            OrderRequest::AddOrder(order, epoch)
+            (order.instrument, OrderRequest::AddOrder(order))
         };
 
-        // 2. Process request
-        let registered = match request.clone() {
-            OrderRequest::CancelOrder(id) => orders.remove_order(id),
-            OrderRequest::AddOrder(order, epoch) => Some(orders.add_get_order(order, epoch)),
-            _ => unimplemented!("modify orders not supported"),
-        }
-        .unwrap_or_else(|| panic!("Mismatching order {} {:?}", i, request));
-
-        // 3. Add to batch for processing
-        match (registered.order_type, request) {
-            (_, OrderRequest::CancelOrder(id)) => {
-                cancel_ids.insert(id, ());
+        // 2. Register request and add to batch for processing
+        match registry.submit(instrument, request) {
+            SubmitResult::Added(order) => {
+                add_count += 1;
+                match order.order_type {
+                    OrderType::Buy => live_bids.push((instrument, order.id)),
+                    OrderType::Sell => live_asks.push((instrument, order.id)),
+                }
             }
-            (OrderType::Buy, OrderRequest::AddOrder(..)) => {
-                buy_batch.push(registered);
+            SubmitResult::Cancelled(_) => cancel_count += 1,
+            SubmitResult::Modified(ModifyResult::Reduced(_) | ModifyResult::Requeued(_)) => {
+                modify_count += 1
             }
-            (OrderType::Sell, OrderRequest::AddOrder(..)) => {
-                sell_batch.push(registered);
+            // Order was already filled in one of previous auctions
+            SubmitResult::UnknownOrder | SubmitResult::Modified(ModifyResult::AlreadyFilled) => {
+                continue
             }
-            _ => {}
+            result => panic!("Unexpected result for order {}: {:?}", i, result),
         }
 
-        // 4. Submit batch on condition
-        if buy_batch.len() + sell_batch.len() >= BATCH_SIZE
-            && period.elapsed().as_nanos() < EPOCH_NS
-        {
-            rayon::join(
-                || {
-                    bids.add_batch(&mut buy_batch);
-                    bids.remove_batch(&cancel_ids);
-                },
-                || {
-                    asks.add_batch(&mut sell_batch);
-                    asks.remove_batch(&cancel_ids);
-                },
-            );
-            cancel_ids.clear();
+        // 3. Submit batch on condition
+        if registry.pending() >= BATCH_SIZE && period.elapsed().as_nanos() < EPOCH_NS {
+            registry.flush();
+            // Indicative price feed of the call period
+            let mut indicative: Vec<_> = registry.indicative().into_iter().collect();
+            indicative.sort_by_key(|(instrument, _)| *instrument);
+            for (instrument, result) in indicative {
+                if let Ok(indicative) = result {
+                    println!(
+                        "Instrument {}: indicative price {} volume {} imbalance {}",
+                        instrument,
+                        indicative.rate,
+                        indicative.volume,
+                        indicative.imbalance(),
+                    );
+                }
+            }
         } else
         // Process market every EPOCH_NS nanos
         if period.elapsed().as_nanos() >= EPOCH_NS {
             let processing_t = Instant::now();
-            println!("## Processing auction. Total {} open orders after clearing {} cancel orders.", orders.len(), cancel_count);
-
-            rayon::join(
-                || {
-                    bids.add_batch(&mut buy_batch);
-                    bids.remove_batch(&cancel_ids);
-                },
-                || {
-                    asks.add_batch(&mut sell_batch);
-                    asks.remove_batch(&cancel_ids);
-                },
+            println!(
+                "## Processing auction. Total {} open orders after {} cancel and {} modify orders.",
+                registry.open_orders(),
+                cancel_count,
+                modify_count
             );
-            cancel_ids.clear();
+
+            registry.flush();
             println!(
                 "Finished final sorting in {} µs",
                 processing_t.elapsed().as_micros()
             );
-            // 5. Market equilibrium
-
-            let match_result = market_match(
-                std::mem::replace(&mut bids, SortedOrders::new(OrderType::Buy)),
-                std::mem::replace(&mut asks, SortedOrders::new(OrderType::Sell)),
-            );
+            // 4. Market equilibrium
 
+            let mut trades = 0;
+            let matching_t = Instant::now();
+            let mut results: Vec<_> = registry.run_auctions().into_iter().collect();
             println!(
-                "Matched {} buy orders with {} sell orders with total volume {} on price {:?}.",
-                match_result.bids_matched,
-                match_result.asks_matched,
-                match_result.traded_volume,
-                match_result.traded_rate,
+                "Matched {} markets in {} µs",
+                results.len(),
+                matching_t.elapsed().as_micros()
             );
-            println!("Cleared {} orders", match_result.trades.len());
-            println!(
-                "Stays open {} buy orders and {} sell orders",
-                match_result.open_bids.len(),
-                match_result.open_asks.len(),
-            );
-
-            bids = match_result.open_bids;
-            asks = match_result.open_asks;
+            results.sort_by_key(|(instrument, _)| *instrument);
+            for (instrument, result) in results {
+                match result {
+                    Ok(match_result) => {
+                        println!(
+                            "Instrument {}: matched {} buy orders with {} sell orders with total volume {} on price {:?}.",
+                            instrument,
+                            match_result.bids_matched,
+                            match_result.asks_matched,
+                            match_result.traded_volume,
+                            match_result.traded_rate,
+                        );
+                        trades += match_result.trades.len();
+                    }
+                    Err(error) => println!("Instrument {}: no trade: {}", instrument, error),
+                }
+                let engine = registry.engine(instrument).unwrap();
+                println!(
+                    "Instrument {}: stays open {} buy orders and {} sell orders, expired {}, triggered {} stop orders",
+                    instrument,
+                    engine.bids().len(),
+                    engine.asks().len(),
+                    engine.expired().len(),
+                    engine.triggered().len(),
+                );
+            }
+            println!("Cleared {} orders", trades);
+            epoch += 1;
 
             stats.add_period(
                 processing_t.elapsed(),
                 period.elapsed(),
-                match_result.trades.len(),
+                trades,
                 add_count,
                 cancel_count,
             );
@@ -197,25 +248,15 @@ fn main() {
             println!("Period completed in {} ms", period.elapsed().as_millis());
 
             period = Instant::now();
-            epoch += 1;
             cancel_count = 0;
+            modify_count = 0;
             add_count = 0;
-            // Clear all orders processed in previous auction
-            for deal in match_result.trades.iter() {
-                if deal.quantity == deal.order.quantity {
-                    orders.remove_order(deal.order.id);
-                } else {
-                    let mut order = deal.order.clone();
-                    order.quantity = deal.quantity;
-                    orders.modify_order(order);
-                }
-            }
             println!(
                 "\n \
                 Starting epoch {} with {} open orders.\n \
                 Current input order N {}",
                 epoch,
-                orders.len(),
+                registry.open_orders(),
                 i
             );
         }
@@ -230,7 +271,14 @@ fn main() {
 }
 
 impl Stats {
-    pub fn add_period(&mut self, processing: Duration, period: Duration, trades: usize, add_count: usize, cancel_count: usize) {
+    pub fn add_period(
+        &mut self,
+        processing: Duration,
+        period: Duration,
+        trades: usize,
+        add_count: usize,
+        cancel_count: usize,
+    ) {
         self.processing.push(processing);
         self.period.push(period);
         self.number_trades.push(trades);
@@ -254,33 +302,33 @@ impl Display for Stats {
         let trades: Vec<_> = self.number_trades.iter().map(|t| *t as f64).collect();
         let adds: Vec<_> = self.add_count.iter().map(|t| *t as f64).collect();
         let cancels: Vec<_> = self.cancel_count.iter().map(|t| *t as f64).collect();
-        write!(
+        writeln!(
             f,
-            "Processing time: mean {:.3}ms dev {:.3}\n",
+            "Processing time: mean {:.3}ms dev {:.3}",
             mean(&processing),
             standard_deviation(&processing, None)
         )?;
-        write!(
+        writeln!(
             f,
-            "Period time including processing: mean {:.3}ms dev {:.3}\n",
+            "Period time including processing: mean {:.3}ms dev {:.3}",
             mean(&periods),
             standard_deviation(&periods, None)
         )?;
-        write!(
+        writeln!(
             f,
-            "Number of trades per period: mean {:.1} dev {:.1}\n",
+            "Number of trades per period: mean {:.1} dev {:.1}",
             mean(&trades),
             standard_deviation(&trades, None)
         )?;
-        write!(
+        writeln!(
             f,
-            "Number of add orders per period: mean {:.1} dev {:.1}\n",
+            "Number of add orders per period: mean {:.1} dev {:.1}",
             mean(&adds),
             standard_deviation(&adds, None)
         )?;
-        write!(
+        writeln!(
             f,
-            "Number of cancelled orders per period: mean {:.1} dev {:.1}\n",
+            "Number of cancelled orders per period: mean {:.1} dev {:.1}",
             mean(&cancels),
             standard_deviation(&cancels, None)
         )
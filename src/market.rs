@@ -1,175 +1,953 @@
-use std::time::Instant;
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    convert::TryFrom,
+    fmt::{self, Display, Formatter},
+};
+
+use slotmap::SparseSecondaryMap;
 
 use crate::{
-    orders::{OrderType, Price, RegisteredOrder},
+    allocation::{allocate_level, AllocationPolicy, IcebergAllocation},
+    book::{level_volumes, LevelVolume, OrderBookSide},
+    collar::{Interruption, PriceCollar, TradingState},
+    fixed::{PriceValue, QuantityValue},
+    orders::{AccountId, OrderId, OrderKind, OrderType, Price, Quantity, RegisteredOrder},
+    pricing::{CallAuctionPricing, PricingContext, PricingRule},
     sorted_vec_orders::SortedOrders,
+    spec::InstrumentSpec,
 };
-use merging_iterator::MergeIter;
 
 #[derive(Debug)]
-pub struct Trade {
-    pub order: RegisteredOrder,
-    pub rate: Price,
-    pub quantity: u32,
+pub struct Trade<P = Price, Q = Quantity> {
+    pub order: RegisteredOrder<P, Q>,
+    pub rate: P,
+    pub quantity: Q,
+}
+
+/// Rule of the call auction algorithm applied to choose clearing price
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClearingRule {
+    /// Prices with maximum executable volume
+    MaximumVolume,
+    /// Prices with minimum surplus (imbalance) among maximum volume ones
+    MinimumSurplus,
+    /// Highest price on buy surplus, lowest price on sell surplus
+    MarketPressure,
+    /// Reference price if it falls within candidates, else closest candidate
+    ReferencePrice,
+    /// Midpoint of candidates when there is no reference price
+    Midpoint,
+    /// Price set by configured pricing rule
+    PricingRule,
+    /// Marginal trade dropped, buyers pay marginal bid and sellers receive marginal ask
+    TradeReduction,
+}
+
+/// Candidate clearing prices left after applying rule
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClearingStep<P = Price> {
+    pub rule: ClearingRule,
+    pub lowest: P,
+    pub highest: P,
+    pub candidates: usize,
+}
+
+/// Aggregated demand (bids at or above rate) and supply (asks at or below rate)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PriceLevel<P = Price> {
+    pub rate: P,
+    pub demand: u64,
+    pub supply: u64,
+}
+
+/// Auction mechanism run by the engine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mechanism {
+    /// Uniform price call auction, see `market_match`
+    CallAuction,
+    /// Truthful trade reduction auction, see `mcafee::mcafee_match`
+    McAfee,
+}
+
+/// Handling of crossing buy and sell orders of the same account
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SelfTradePrevention {
+    /// Orders of the same account may trade with each other
+    #[default]
+    Allow,
+    /// Cancel the order which arrived later
+    CancelNewest,
+    /// Cancel the order which arrived earlier
+    CancelOldest,
+    /// Cancel both orders
+    CancelBoth,
+    /// Reduce both orders by the smaller quantity, cancelling the smaller one
+    DecrementAndCancel,
+}
+
+/// Order taken off the book by self trade prevention
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfTrade<P = Price, Q = Quantity> {
+    /// Order as it was before prevention
+    pub order: RegisteredOrder<P, Q>,
+    /// Quantity taken off, equals order quantity when order was cancelled
+    pub reduced: Q,
+}
+
+/// Clearing when market orders alone cross, so there is no limit price to set clearing price
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MarketOrderClearing {
+    /// Execute market orders at reference price, no trade without reference price
+    #[default]
+    ReferencePrice,
+    NoTrade,
+}
+
+/// Order with fill condition left out of the auction, as at clearing `rate`
+/// only `executable` quantity could be filled
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkippedOrder<P = Price, Q = Quantity> {
+    pub order: RegisteredOrder<P, Q>,
+    pub rate: P,
+    pub executable: Q,
+}
+
+/// Per market auction settings
+pub struct MatchConfig<P = Price, Q = Quantity> {
+    /// Orders not conforming to spec are rejected on submit
+    pub spec: InstrumentSpec<P, Q>,
+    pub mechanism: Mechanism,
+    pub pricing: Box<dyn PricingRule<P, Q>>,
+    /// Allocation among orders at the marginal price level
+    pub allocation: AllocationPolicy,
+    /// Allocation between displayed and hidden quantity at the marginal price level
+    pub iceberg: IcebergAllocation,
+    pub self_trade_prevention: SelfTradePrevention,
+    pub market_orders: MarketOrderClearing,
+    /// Bands clearing price has to stay within, see `collar`
+    pub collar: PriceCollar<P>,
+}
+
+/// Outcome the auction would have on current books
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndicativeMatch<P = Price> {
+    pub rate: P,
+    /// Volume executed at `rate`
+    pub volume: u64,
+    /// Bids at or above `rate`
+    pub demand: u64,
+    /// Asks at or below `rate`
+    pub supply: u64,
+    /// Price collar breach the auction would end with
+    pub interruption: Option<Interruption<P>>,
 }
 
-pub struct MarketMatchResult {
-    pub open_bids: SortedOrders,
-    pub open_asks: SortedOrders,
-    pub trades: Vec<Trade>,
+#[derive(Debug)]
+pub struct MarketMatchResult<P = Price, Q = Quantity, B = SortedOrders<P, Q>> {
+    pub open_bids: B,
+    pub open_asks: B,
+    pub trades: Vec<Trade<P, Q>>,
     pub traded_volume: u64,
-    pub traded_rate: Option<Price>,
+    pub traded_rate: Option<P>,
     pub bids_matched: usize,
     pub asks_matched: usize,
+    /// Demand minus supply at traded rate
+    pub surplus: i64,
+    /// Steps of the clearing algorithm which determined traded rate
+    pub steps: Vec<ClearingStep<P>>,
+    /// Difference between paid by buyers and received by sellers, kept by auctioneer,
+    /// scaled by both price and quantity scale
+    pub budget_surplus: u128,
+    /// Orders cancelled or reduced by self trade prevention before clearing
+    pub self_trades: Vec<SelfTrade<P, Q>>,
+    /// Orders whose fill condition could not be met, they stay in open books
+    pub skipped: Vec<SkippedOrder<P, Q>>,
+}
+
+/// Reason auction could not trade
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchErrorKind<P = Price> {
+    /// Both books are empty
+    EmptyBook,
+    /// Only given side has orders
+    OneSidedBook(OrderType),
+    /// Best bid is below best ask
+    NoCross { best_bid: P, best_ask: P },
+    /// Aggregated volume does not fit into i64
+    VolumeOverflow,
+    /// Only market orders cross and clearing price can not be set
+    NoLimitPrice,
+    /// Auction mechanism does not support orders of the kind
+    UnsupportedOrderKind(OrderKind<P>),
+    /// Clearing price is outside of price collar
+    VolatilityInterruption(Interruption<P>),
+    /// Engine skipped the auction after interruption
+    Halted(TradingState),
 }
 
-impl MarketMatchResult {
-    fn no_trade(open_bids: SortedOrders, open_asks: SortedOrders) -> Self {
+/// Failed auction, hands books back without trades
+#[derive(Debug)]
+pub struct MatchError<P = Price, Q = Quantity, B = SortedOrders<P, Q>> {
+    pub kind: MatchErrorKind<P>,
+    pub open_bids: B,
+    pub open_asks: B,
+    /// Orders cancelled or reduced by self trade prevention, already applied to books
+    pub self_trades: Vec<SelfTrade<P, Q>>,
+    /// Orders whose fill condition could not be met, books might not cross without them
+    pub skipped: Vec<SkippedOrder<P, Q>>,
+}
+
+impl<P: PriceValue, Q: QuantityValue, B: OrderBookSide<P, Q>> MatchError<P, Q, B> {
+    pub(crate) fn new(kind: MatchErrorKind<P>, open_bids: B, open_asks: B) -> Self {
         Self {
+            kind,
             open_bids,
             open_asks,
-            trades: Default::default(),
-            traded_volume: 0,
-            traded_rate: None,
-            bids_matched: 0,
-            asks_matched: 0,
+            self_trades: Vec::new(),
+            skipped: Vec::new(),
         }
     }
 }
 
-pub fn market_match(mut bids: SortedOrders, mut asks: SortedOrders) -> MarketMatchResult {
-    let time1 = Instant::now();
-    let bids_iter = bids.iter().map(aggregate_quantity());
-    let asks_iter = asks.iter().map(aggregate_quantity());
-    // Merged iterator of buy vs sell over balanced quantity
-    let balanced_orders = MergeIter::with_custom_ordering(
-        bids_iter,
-        asks_iter,
-        |(_, bid_volume), (_, ask_volume)| bid_volume < ask_volume,
-    );
-    // Walk through demand and supply while prices fit
-    let mut bid = Price::MAX;
-    let mut ask = Price::MIN;
-    let mut bid_idx: usize = 0;
-    let mut ask_idx: usize = 0;
-    let mut bid_volume: u64 = 0;
-    let mut ask_volume: u64 = 0;
-    let total_matched = balanced_orders
-        .take_while(|(order, volume)| match order.order_type {
-            OrderType::Buy if order.rate >= ask => {
-                bid_volume = *volume;
-                bid_idx += 1;
-                bid = order.rate;
-                true
+impl<P: PriceValue> Display for MatchErrorKind<P> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MatchErrorKind::EmptyBook => write!(f, "order books are empty"),
+            MatchErrorKind::OneSidedBook(order_type) => {
+                write!(f, "only {:?} orders in the book", order_type)
             }
-            OrderType::Sell if bid >= order.rate => {
-                ask_volume = *volume;
-                ask = order.rate;
-                ask_idx += 1;
-                true
+            MatchErrorKind::NoCross { best_bid, best_ask } => write!(
+                f,
+                "best bid {} does not cross best ask {}",
+                best_bid, best_ask
+            ),
+            MatchErrorKind::VolumeOverflow => write!(f, "aggregated volume overflow"),
+            MatchErrorKind::NoLimitPrice => write!(f, "only market orders cross"),
+            MatchErrorKind::UnsupportedOrderKind(kind) => {
+                write!(f, "{:?} orders are not supported", kind)
             }
-            _ => false,
-        })
-        .count();
-    bid_idx -= 1;
-    ask_idx -= 1;
-    println!(
-        "Approximate equilibrium at bid[{}] ask[{}] in {}micros",
-        bid_idx,
-        ask_idx,
-        time1.elapsed().as_micros()
+            MatchErrorKind::VolatilityInterruption(interruption) => write!(
+                f,
+                "clearing price {} is outside of {:?} band [{}, {}]",
+                interruption.rate, interruption.band, interruption.lowest, interruption.highest
+            ),
+            MatchErrorKind::Halted(state) => write!(f, "auction is halted: {:?}", state),
+        }
+    }
+}
+
+impl<P: PriceValue, Q: QuantityValue, B> Display for MatchError<P, Q, B> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.kind, f)
+    }
+}
+
+impl<P: PriceValue> std::error::Error for MatchErrorKind<P> {}
+impl<P: PriceValue, Q: QuantityValue, B: OrderBookSide<P, Q>> std::error::Error
+    for MatchError<P, Q, B>
+{
+}
+
+impl<P: PriceValue, Q: QuantityValue> Default for MatchConfig<P, Q> {
+    fn default() -> Self {
+        Self {
+            spec: Default::default(),
+            mechanism: Mechanism::CallAuction,
+            pricing: Box::new(CallAuctionPricing),
+            allocation: Default::default(),
+            iceberg: Default::default(),
+            self_trade_prevention: Default::default(),
+            market_orders: Default::default(),
+            collar: Default::default(),
+        }
+    }
+}
+
+impl<P> IndicativeMatch<P> {
+    /// Demand minus supply at indicative rate
+    #[inline]
+    pub fn imbalance(&self) -> i64 {
+        self.demand as i64 - self.supply as i64
+    }
+
+    /// Side with volume left unexecuted, None when demand equals supply
+    #[inline]
+    pub fn imbalance_side(&self) -> Option<OrderType> {
+        match self.demand.cmp(&self.supply) {
+            Ordering::Greater => Some(OrderType::Buy),
+            Ordering::Less => Some(OrderType::Sell),
+            Ordering::Equal => None,
+        }
+    }
+}
+
+impl<P> PriceLevel<P> {
+    #[inline]
+    pub fn volume(&self) -> u64 {
+        self.demand.min(self.supply)
+    }
+
+    #[inline]
+    pub fn surplus(&self) -> i64 {
+        self.demand as i64 - self.supply as i64
+    }
+}
+
+/// Uniform price call auction: clearing price maximises executable volume,
+/// then is chosen by configured pricing rule, by default minimum surplus,
+/// then market pressure, then reference price.
+/// All orders are executed at the same price in price priority, market orders first.
+/// Crossing orders of the same account are resolved first according to
+/// configured self trade prevention.
+/// Demand and supply are read from price levels of the books, only orders of levels
+/// covering executed volume are taken out of them.
+/// Orders whose fill condition can not be met at clearing price are left out
+/// and clearing is repeated without them.
+/// Clearing price outside of configured price collar interrupts the auction,
+/// dynamic band is centred on `reference_price`.
+pub fn market_match<P: PriceValue, Q: QuantityValue, B: OrderBookSide<P, Q>>(
+    mut open_bids: B,
+    mut open_asks: B,
+    reference_price: Option<P>,
+    config: &MatchConfig<P, Q>,
+) -> Result<MarketMatchResult<P, Q, B>, MatchError<P, Q, B>> {
+    let (best_bid, best_ask) = match best_prices(open_bids.first(), open_asks.first()) {
+        Ok(best) => best,
+        Err(kind) => return Err(MatchError::new(kind, open_bids, open_asks)),
+    };
+    // Self trade prevention looks at every crossing order, take them only when it applies
+    let self_trades = if config.self_trade_prevention == SelfTradePrevention::Allow {
+        Vec::new()
+    } else {
+        let mut bids = take_crossing(&mut open_bids, |rate| rate >= best_ask);
+        let mut asks = take_crossing(&mut open_asks, |rate| rate <= best_bid);
+        let self_trades = prevent_self_trades(&mut bids, &mut asks, config.self_trade_prevention);
+        put_back(&mut open_bids, bids);
+        put_back(&mut open_asks, asks);
+        self_trades
+    };
+    let mut skipped = Vec::new();
+    let (mut held_bids, mut held_asks) = (Vec::new(), Vec::new());
+    let cleared = loop {
+        let (best_bid, best_ask) = match best_prices(open_bids.first(), open_asks.first()) {
+            Ok(best) => best,
+            Err(kind) => break Err(kind),
+        };
+        let bid_levels = open_bids.volumes_while(|rate| rate >= best_ask);
+        let ask_levels = open_asks.volumes_while(|rate| rate <= best_bid);
+        let levels = match level_curves(&bid_levels, &ask_levels) {
+            Ok(levels) => levels,
+            Err(kind) => break Err(kind),
+        };
+        // Pricing rules only see orders executed at maximum volume
+        let max_volume = levels.iter().map(PriceLevel::volume).max().unwrap_or(0);
+        let mut bids = take_levels(&mut open_bids, &bid_levels, max_volume);
+        let mut asks = take_levels(&mut open_asks, &ask_levels, max_volume);
+        let best = (best_bid, best_ask);
+        let clearing = clear_levels(&bids, &asks, &levels, best, reference_price, config);
+        let (rate, steps) = match clearing {
+            Ok(clearing) => clearing,
+            Err(kind) => {
+                put_back(&mut open_bids, bids);
+                put_back(&mut open_asks, asks);
+                break Err(kind);
+            }
+        };
+        // Taken orders are a prefix of the book, volume of the rest is read from the book
+        let demand =
+            volume_while(&bids, |order| order.rate >= rate) + open_bids.volume_at(rate) as u64;
+        let supply =
+            volume_while(&asks, |order| order.rate <= rate) + open_asks.volume_at(rate) as u64;
+        let volume = demand.min(supply);
+        // Market orders alone trade at reference price with no level at maximum volume
+        let more_bids = levels_len(&bid_levels, volume).saturating_sub(bids.len());
+        let more_asks = levels_len(&ask_levels, volume).saturating_sub(asks.len());
+        bids.extend(open_bids.drain_prefix(more_bids));
+        asks.extend(open_asks.drain_prefix(more_asks));
+        let unmet_bids = unmet_conditions(&bids, volume, config.allocation, config.iceberg);
+        let unmet_asks = unmet_conditions(&asks, volume, config.allocation, config.iceberg);
+        if unmet_bids.is_empty() && unmet_asks.is_empty() {
+            // Orders left out might move clearing price, collar applies to the final one
+            if let Some(interruption) = config.collar.check(rate, reference_price) {
+                put_back(&mut open_bids, bids);
+                put_back(&mut open_asks, asks);
+                break Err(MatchErrorKind::VolatilityInterruption(interruption));
+            }
+            break Ok((rate, steps, demand, supply, bids, asks));
+        }
+        for (orders, held, unmet) in [
+            (&mut bids, &mut held_bids, unmet_bids),
+            (&mut asks, &mut held_asks, unmet_asks),
+        ] {
+            for (idx, executable) in unmet.into_iter().rev() {
+                let order = orders.remove(idx);
+                skipped.push(SkippedOrder {
+                    order: order.clone(),
+                    rate,
+                    executable,
+                });
+                held.push(order);
+            }
+        }
+        put_back(&mut open_bids, bids);
+        put_back(&mut open_asks, asks);
+    };
+    let (rate, steps, demand, supply, mut bids, mut asks) = match cleared {
+        Ok(cleared) => cleared,
+        Err(kind) => {
+            restore(&mut open_bids, &mut held_bids);
+            restore(&mut open_asks, &mut held_asks);
+            let mut error = MatchError::new(kind, open_bids, open_asks);
+            error.self_trades = self_trades;
+            error.skipped = skipped;
+            return Err(error);
+        }
+    };
+    let traded_volume = demand.min(supply);
+
+    let mut trades = Vec::new();
+    let bids_matched = execute(
+        &mut bids,
+        traded_volume,
+        rate,
+        config.allocation,
+        config.iceberg,
+        &mut trades,
     );
+    let asks_matched = execute(
+        &mut asks,
+        traded_volume,
+        rate,
+        config.allocation,
+        config.iceberg,
+        &mut trades,
+    );
+    put_back(&mut open_bids, bids);
+    put_back(&mut open_asks, asks);
+    restore(&mut open_bids, &mut held_bids);
+    restore(&mut open_asks, &mut held_asks);
+    Ok(MarketMatchResult {
+        open_bids,
+        open_asks,
+        trades,
+        traded_volume,
+        traded_rate: Some(rate),
+        bids_matched,
+        asks_matched,
+        surplus: demand as i64 - supply as i64,
+        steps,
+        budget_surplus: 0,
+        self_trades,
+        skipped,
+    })
+}
+
+/// Indicative price, volume and imbalance of the auction without touching the books.
+/// Fill conditions and self trade prevention are not applied, auction might clear
+/// at a different price when they exclude orders.
+pub fn indicative_match<P: PriceValue, Q: QuantityValue, B: OrderBookSide<P, Q>>(
+    bids: &B,
+    asks: &B,
+    reference_price: Option<P>,
+    config: &MatchConfig<P, Q>,
+) -> Result<IndicativeMatch<P>, MatchErrorKind<P>> {
+    let (best_bid, best_ask) = best_prices(bids.first(), asks.first())?;
+    let bid_levels = bids.volumes_while(|rate| rate >= best_ask);
+    let ask_levels = asks.volumes_while(|rate| rate <= best_bid);
+    let levels = level_curves(&bid_levels, &ask_levels)?;
+    // Pricing rules only see executed orders, copy just them
+    let max_volume = levels.iter().map(PriceLevel::volume).max().unwrap_or(0);
+    let executed = |book: &B| -> Vec<_> {
+        let count = executed_len(book.iter(), max_volume);
+        book.iter().take(count).cloned().collect()
+    };
+    let (rate, _) = clear_levels(
+        &executed(bids),
+        &executed(asks),
+        &levels,
+        (best_bid, best_ask),
+        reference_price,
+        config,
+    )?;
+    // Crossing volume fits i64 as checked by level curves
+    let demand = bids.volume_at(rate) as u64;
+    let supply = asks.volume_at(rate) as u64;
+    Ok(IndicativeMatch {
+        rate,
+        volume: demand.min(supply),
+        demand,
+        supply,
+        interruption: config.collar.check(rate, reference_price),
+    })
+}
+
+/// Clearing price of crossing books with best prices `best` and their price levels,
+/// orders of books need to cover volume executed at the levels only
+fn clear_levels<P: PriceValue, Q: QuantityValue>(
+    bids: &[RegisteredOrder<P, Q>],
+    asks: &[RegisteredOrder<P, Q>],
+    levels: &[PriceLevel<P>],
+    (best_bid, best_ask): (P, P),
+    reference_price: Option<P>,
+    config: &MatchConfig<P, Q>,
+) -> Result<(P, Vec<ClearingStep<P>>), MatchErrorKind<P>> {
+    if levels.is_empty() {
+        return market_orders_price(reference_price, config).ok_or(MatchErrorKind::NoLimitPrice);
+    }
+    clearing_price(bids, asks, levels, reference_price, config)
+        .ok_or(MatchErrorKind::NoCross { best_bid, best_ask })
+}
+
+/// Take orders at prices which `crosses` out of the book, with the best order behind
+/// crossing ones so that books which stop crossing during the auction
+/// still tell their best price. Auction never reaches further orders.
+pub(crate) fn take_crossing<P: PriceValue, Q: QuantityValue, B: OrderBookSide<P, Q>>(
+    book: &mut B,
+    crosses: impl Fn(P) -> bool,
+) -> Vec<RegisteredOrder<P, Q>> {
+    let crossing: usize = book
+        .volumes_while(crosses)
+        .iter()
+        .map(|level| level.orders)
+        .sum();
+    book.drain_prefix((crossing + 1).min(book.len()))
+}
+
+/// Take orders of the best price levels covering `volume` out of the book,
+/// so marginal level is taken whole
+fn take_levels<P: PriceValue, Q: QuantityValue, B: OrderBookSide<P, Q>>(
+    book: &mut B,
+    levels: &[LevelVolume<P>],
+    volume: u64,
+) -> Vec<RegisteredOrder<P, Q>> {
+    book.drain_prefix(levels_len(levels, volume))
+}
 
-    if total_matched < 2 {
-        return MarketMatchResult::no_trade(bids, asks);
-    }
-
-    let time2 = Instant::now();
-    // We just need
-    let mut deals = Vec::new();
-    let mut bid_orders_matched = 0;
-    let mut ask_orders_matched = 0;
-    let traded_volume;
-    let (mut bid, mut ask) = (&bids[bid_idx], &asks[ask_idx]);
-    // Market rate
-    let rate = (bid.rate + ask.rate) / 2;
-    let traded_rate = Some(rate);
-    // One order might be only partially filled if resulting
-    // demand / supply quanity does not match
-    if bid_volume > ask_volume {
-        // Go down on buy orders until we find one which would match sell volume
-        while bid_volume - bid.quantity as u64 > ask_volume {
-            bid_volume -= bid.quantity as u64;
-            bid_idx -= 1;
-            bid = &bids[bid_idx];
+/// Number of orders in the best price levels covering `volume`
+fn levels_len<P: PriceValue>(levels: &[LevelVolume<P>], volume: u64) -> usize {
+    let mut total: u128 = 0;
+    levels
+        .iter()
+        .take_while(|level| {
+            let before = total;
+            total += level.quantity;
+            before < volume as u128
+        })
+        .map(|level| level.orders)
+        .sum()
+}
+
+/// Put orders taken by `take_crossing` and left after the auction back in front of the book
+#[inline]
+pub(crate) fn put_back<P: PriceValue, Q: QuantityValue, B: OrderBookSide<P, Q>>(
+    book: &mut B,
+    taken: Vec<RegisteredOrder<P, Q>>,
+) {
+    book.prepend_batch(taken);
+}
+
+/// Put orders left out of the auction back to the book
+#[inline]
+pub(crate) fn restore<P: PriceValue, Q: QuantityValue, B: OrderBookSide<P, Q>>(
+    book: &mut B,
+    held: &mut Vec<RegisteredOrder<P, Q>>,
+) {
+    if !held.is_empty() {
+        book.insert_batch(held);
+    }
+}
+
+/// Cancel or reduce crossing orders of the same account, walking each account's
+/// bids from the highest and asks from the lowest while they cross.
+/// Returns affected orders, books keep only orders with quantity left.
+pub fn prevent_self_trades<P: PriceValue, Q: QuantityValue>(
+    bids: &mut Vec<RegisteredOrder<P, Q>>,
+    asks: &mut Vec<RegisteredOrder<P, Q>>,
+    mode: SelfTradePrevention,
+) -> Vec<SelfTrade<P, Q>> {
+    if mode == SelfTradePrevention::Allow {
+        return Vec::new();
+    }
+    let (best_bid, best_ask) = match best_prices(bids.first(), asks.first()) {
+        Ok(best) => best,
+        Err(_) => return Vec::new(),
+    };
+    let crossing_bids = bids.partition_point(|order| order.rate >= best_ask);
+    let crossing_asks = asks.partition_point(|order| order.rate <= best_bid);
+    let mut accounts: HashMap<AccountId, (Vec<usize>, Vec<usize>)> = HashMap::new();
+    for (idx, order) in bids[..crossing_bids].iter().enumerate() {
+        accounts.entry(order.account).or_default().0.push(idx);
+    }
+    for (idx, order) in asks[..crossing_asks].iter().enumerate() {
+        if let Some((_, account_asks)) = accounts.get_mut(&order.account) {
+            account_asks.push(idx);
         }
-        traded_volume = ask_volume;
-        deals.push(Trade {
-            quantity: (bid.quantity as u64 + ask_volume - bid_volume) as u32,
-            rate,
-            order: bid.clone(),
-        });
-        bid_idx -= 1;
-        bid_orders_matched += 1;
-    } else if bid_volume < ask_volume {
-        // Go down on sell orders until we find one which would match buy volume
-        while ask_volume - ask.quantity as u64 > bid_volume {
-            ask_volume -= ask.quantity as u64;
-            ask_idx -= 1;
-            ask = &asks[ask_idx];
+    }
+
+    let mut affected: SparseSecondaryMap<OrderId, SelfTrade<P, Q>> = SparseSecondaryMap::new();
+    let mut reduce = |order: &mut RegisteredOrder<P, Q>, quantity: Q| {
+        affected
+            .entry(order.id)
+            .unwrap()
+            .or_insert_with(|| SelfTrade {
+                order: order.clone(),
+                reduced: Q::ZERO,
+            })
+            .reduced += quantity;
+        order.set_quantity(order.quantity - quantity);
+    };
+    for (account_bids, account_asks) in accounts.values() {
+        let (mut bid_idx, mut ask_idx) = (0, 0);
+        while bid_idx < account_bids.len() && ask_idx < account_asks.len() {
+            let bid = &mut bids[account_bids[bid_idx]];
+            let ask = &mut asks[account_asks[ask_idx]];
+            if bid.rate < ask.rate {
+                break;
+            }
+            let bid_is_newer = (bid.epoch, bid.sequence) > (ask.epoch, ask.sequence);
+            let (bid_reduced, ask_reduced) = match mode {
+                SelfTradePrevention::Allow => unreachable!(),
+                SelfTradePrevention::CancelNewest if bid_is_newer => (bid.quantity, Q::ZERO),
+                SelfTradePrevention::CancelNewest => (Q::ZERO, ask.quantity),
+                SelfTradePrevention::CancelOldest if bid_is_newer => (Q::ZERO, ask.quantity),
+                SelfTradePrevention::CancelOldest => (bid.quantity, Q::ZERO),
+                SelfTradePrevention::CancelBoth => (bid.quantity, ask.quantity),
+                SelfTradePrevention::DecrementAndCancel => {
+                    let quantity = bid.quantity.min(ask.quantity);
+                    (quantity, quantity)
+                }
+            };
+            if bid_reduced > Q::ZERO {
+                reduce(bid, bid_reduced);
+            }
+            if ask_reduced > Q::ZERO {
+                reduce(ask, ask_reduced);
+            }
+            bid_idx += (bid.quantity == Q::ZERO) as usize;
+            ask_idx += (ask.quantity == Q::ZERO) as usize;
+        }
+    }
+    if !affected.is_empty() {
+        bids.retain(|order| order.quantity > Q::ZERO);
+        asks.retain(|order| order.quantity > Q::ZERO);
+    }
+    affected.drain().map(|(_, self_trade)| self_trade).collect()
+}
+
+/// Best bid and best ask of crossing books, given their best orders
+pub(crate) fn best_prices<P: PriceValue, Q: QuantityValue>(
+    best_bid: Option<&RegisteredOrder<P, Q>>,
+    best_ask: Option<&RegisteredOrder<P, Q>>,
+) -> Result<(P, P), MatchErrorKind<P>> {
+    match (best_bid, best_ask) {
+        (Some(bid), Some(ask)) if bid.rate >= ask.rate => Ok((bid.rate, ask.rate)),
+        (Some(bid), Some(ask)) => Err(MatchErrorKind::NoCross {
+            best_bid: bid.rate,
+            best_ask: ask.rate,
+        }),
+        (Some(_), None) => Err(MatchErrorKind::OneSidedBook(OrderType::Buy)),
+        (None, Some(_)) => Err(MatchErrorKind::OneSidedBook(OrderType::Sell)),
+        (None, None) => Err(MatchErrorKind::EmptyBook),
+    }
+}
+
+/// Demand and supply at every limit price within crossing range of the books, ascending.
+/// Market orders count in demand and supply of every level, but do not make levels.
+pub fn price_levels<P: PriceValue, Q: QuantityValue>(
+    bids: &[RegisteredOrder<P, Q>],
+    asks: &[RegisteredOrder<P, Q>],
+) -> Result<Vec<PriceLevel<P>>, MatchErrorKind<P>> {
+    let (best_bid, best_ask) = best_prices(bids.first(), asks.first())?;
+    let bids = level_volumes(bids.iter().take_while(|order| order.rate >= best_ask));
+    let asks = level_volumes(asks.iter().take_while(|order| order.rate <= best_bid));
+    level_curves(&bids, &asks)
+}
+
+/// Demand and supply at every limit price of crossing price levels, ascending.
+/// Levels of each side are given from the best one, demand and supply are summed
+/// per level rather than per order.
+pub fn level_curves<P: PriceValue>(
+    bids: &[LevelVolume<P>],
+    asks: &[LevelVolume<P>],
+) -> Result<Vec<PriceLevel<P>>, MatchErrorKind<P>> {
+    let total = |levels: &[LevelVolume<P>]| {
+        let total: u128 = levels.iter().map(|level| level.quantity).sum();
+        u64::try_from(total)
+            .ok()
+            .filter(|total| *total <= i64::MAX as u64)
+            .ok_or(MatchErrorKind::VolumeOverflow)
+    };
+    let mut demand: u64 = total(bids)?;
+    // Supply never exceeds total of crossing asks
+    total(asks)?;
+    let (market_asks, asks) = asks.split_at(asks.partition_point(|level| level.market));
+    let bids = &bids[bids.partition_point(|level| level.market)..];
+    let mut supply: u64 = market_asks.iter().map(|level| level.quantity as u64).sum();
+    // Walk both books from the lowest price up
+    let mut bids_iter = bids.iter().rev().peekable();
+    let mut asks_iter = asks.iter().peekable();
+    let mut levels = Vec::new();
+    loop {
+        let rate = match (bids_iter.peek(), asks_iter.peek()) {
+            (Some(bid), Some(ask)) => bid.rate.min(ask.rate),
+            (Some(bid), None) => bid.rate,
+            (None, Some(ask)) => ask.rate,
+            (None, None) => break,
+        };
+        while let Some(ask) = asks_iter.next_if(|ask| ask.rate <= rate) {
+            supply += ask.quantity as u64;
         }
-        traded_volume = bid_volume;
-        deals.push(Trade {
-            quantity: (ask.quantity as u64 + bid_volume - ask_volume) as u32,
+        levels.push(PriceLevel {
             rate,
-            order: ask.clone(),
+            demand,
+            supply,
         });
-        ask_idx -= 1;
-        ask_orders_matched += 1;
-    } else {
-        traded_volume = bid_volume;
+        while let Some(bid) = bids_iter.next_if(|bid| bid.rate <= rate) {
+            demand -= bid.quantity as u64;
+        }
+    }
+    Ok(levels)
+}
+
+/// Choose clearing price from ascending price levels, None if books do not cross
+pub fn clearing_price<P: PriceValue, Q: QuantityValue>(
+    bids: &[RegisteredOrder<P, Q>],
+    asks: &[RegisteredOrder<P, Q>],
+    levels: &[PriceLevel<P>],
+    reference_price: Option<P>,
+    config: &MatchConfig<P, Q>,
+) -> Option<(P, Vec<ClearingStep<P>>)> {
+    let max_volume = levels.iter().map(PriceLevel::volume).max().unwrap_or(0);
+    if max_volume == 0 {
+        return None;
+    }
+    let candidates: Vec<_> = levels
+        .iter()
+        .filter(|level| level.volume() == max_volume)
+        .copied()
+        .collect();
+    let mut steps = vec![ClearingStep::new(ClearingRule::MaximumVolume, &candidates)];
+    let bids = executed(bids, max_volume);
+    let asks = executed(asks, max_volume);
+    // Limit price of marginal order is always a candidate, market orders are bounded
+    // by candidates
+    let context = PricingContext {
+        candidates: &candidates,
+        volume: max_volume,
+        marginal_bid: bids[bids.len() - 1]
+            .rate
+            .min(candidates[candidates.len() - 1].rate),
+        marginal_ask: asks[asks.len() - 1].rate.max(candidates[0].rate),
+        reference_price,
+        tick_size: config.spec.tick_size,
+        bids,
+        asks,
     };
+    let rate = config.pricing.clearing_price(&context, &mut steps);
+    Some((rate, steps))
+}
+
+/// Clearing price when only market orders cross, None if market orders do not trade
+fn market_orders_price<P: PriceValue, Q: QuantityValue>(
+    reference_price: Option<P>,
+    config: &MatchConfig<P, Q>,
+) -> Option<(P, Vec<ClearingStep<P>>)> {
+    match (config.market_orders, reference_price) {
+        (MarketOrderClearing::ReferencePrice, Some(reference)) => Some((
+            reference,
+            vec![ClearingStep::single(
+                ClearingRule::ReferencePrice,
+                reference,
+            )],
+        )),
+        _ => None,
+    }
+}
+
+/// Shortest prefix of orders covering volume
+#[inline]
+fn executed<P: PriceValue, Q: QuantityValue>(
+    orders: &[RegisteredOrder<P, Q>],
+    volume: u64,
+) -> &[RegisteredOrder<P, Q>] {
+    &orders[..executed_len(orders.iter(), volume)]
+}
 
-    bid_orders_matched += bid_idx + 1;
-    ask_orders_matched += ask_idx + 1;
+/// Number of orders in the shortest prefix covering volume
+#[inline]
+fn executed_len<'a, P: PriceValue, Q: QuantityValue>(
+    orders: impl Iterator<Item = &'a RegisteredOrder<P, Q>>,
+    volume: u64,
+) -> usize {
+    let mut total: u64 = 0;
+    orders
+        .take_while(|order| {
+            let before = total;
+            total += order.quantity.to_raw();
+            before < volume
+        })
+        .count()
+}
 
-    deals.extend(
-        bids.drain(0..=bid_idx)
-            .chain(asks.drain(0..=ask_idx))
-            .map(|order| Trade {
+impl<P: PriceValue> ClearingStep<P> {
+    pub(crate) fn new(rule: ClearingRule, candidates: &[PriceLevel<P>]) -> Self {
+        Self {
+            rule,
+            lowest: candidates[0].rate,
+            highest: candidates[candidates.len() - 1].rate,
+            candidates: candidates.len(),
+        }
+    }
+
+    pub(crate) fn single(rule: ClearingRule, rate: P) -> Self {
+        Self {
+            rule,
+            lowest: rate,
+            highest: rate,
+            candidates: 1,
+        }
+    }
+}
+
+#[inline]
+pub(crate) fn add_volume<P: PriceValue, Q: QuantityValue>(
+    volume: u64,
+    order: &RegisteredOrder<P, Q>,
+) -> Result<u64, MatchErrorKind<P>> {
+    volume
+        .checked_add(order.quantity.to_raw())
+        .filter(|volume| *volume <= i64::MAX as u64)
+        .ok_or(MatchErrorKind::VolumeOverflow)
+}
+
+#[inline]
+pub(crate) fn volume_while<P: PriceValue, Q: QuantityValue>(
+    orders: &[RegisteredOrder<P, Q>],
+    predicate: impl Fn(&RegisteredOrder<P, Q>) -> bool,
+) -> u64 {
+    orders
+        .iter()
+        .take_while(|order| predicate(order))
+        .map(|order| order.quantity.to_raw())
+        .sum()
+}
+
+/// Fill `volume` in book price priority at `rate`, orders at marginal price level
+/// share remaining volume according to allocation policy and iceberg rule. Partially filled orders
+/// stay in the book with remaining quantity. Returns number of orders matched.
+pub(crate) fn execute<P: PriceValue, Q: QuantityValue>(
+    orders: &mut Vec<RegisteredOrder<P, Q>>,
+    volume: u64,
+    rate: P,
+    policy: AllocationPolicy,
+    iceberg: IcebergAllocation,
+    trades: &mut Vec<Trade<P, Q>>,
+) -> usize {
+    let (filled, allocated) = fill_plan(orders, volume, policy, iceberg);
+    let mut matched = filled;
+    trades.extend(orders.drain(0..filled).map(|order| Trade {
+        rate,
+        quantity: order.quantity,
+        order,
+    }));
+    if allocated.is_empty() {
+        return matched;
+    }
+
+    // Marginal price level
+    let level_end = allocated.len();
+    for (order, quantity) in orders[..level_end].iter_mut().zip(allocated) {
+        if quantity > Q::ZERO {
+            trades.push(Trade {
+                order: order.clone(),
                 rate,
-                quantity: order.quantity,
-                order,
-            }),
-    );
-    println!(
-        "Built market results in {} micros",
-        time2.elapsed().as_micros()
-    );
-    MarketMatchResult {
-        open_bids: bids,
-        open_asks: asks,
-        trades: deals,
-        traded_volume,
-        traded_rate,
-        bids_matched: bid_orders_matched,
-        asks_matched: ask_orders_matched,
+                quantity,
+            });
+            order.fill(quantity);
+            matched += 1;
+        }
     }
+    if orders[..level_end]
+        .iter()
+        .any(|order| order.quantity == Q::ZERO)
+    {
+        let level: Vec<_> = orders
+            .drain(..level_end)
+            .filter(|order| order.quantity > Q::ZERO)
+            .collect();
+        orders.splice(0..0, level);
+    }
+    matched
 }
 
+/// Number of orders filled in full, as whole price levels are covered by volume,
+/// and allocation of remaining volume among orders of the next, marginal level
+fn fill_plan<P: PriceValue, Q: QuantityValue>(
+    orders: &[RegisteredOrder<P, Q>],
+    volume: u64,
+    policy: AllocationPolicy,
+    iceberg: IcebergAllocation,
+) -> (usize, Vec<Q>) {
+    let mut remaining = volume;
+    let mut filled = 0;
+    while filled < orders.len() && remaining > 0 {
+        let level_end = filled + price_level_len(&orders[filled..]);
+        let level_volume: u64 = orders[filled..level_end]
+            .iter()
+            .map(|order| order.quantity.to_raw())
+            .sum();
+        if level_volume > remaining {
+            break;
+        }
+        remaining -= level_volume;
+        filled = level_end;
+    }
+    if remaining == 0 {
+        return (filled, Vec::new());
+    }
+    let level_end = filled + price_level_len(&orders[filled..]);
+    (
+        filled,
+        allocate_level(policy, iceberg, &orders[filled..level_end], remaining),
+    )
+}
+
+/// Indexes of orders which would be filled below their minimum quantity,
+/// with quantity they would be filled with
+pub(crate) fn unmet_conditions<P: PriceValue, Q: QuantityValue>(
+    orders: &[RegisteredOrder<P, Q>],
+    volume: u64,
+    policy: AllocationPolicy,
+    iceberg: IcebergAllocation,
+) -> Vec<(usize, Q)> {
+    let (filled, allocated) = fill_plan(orders, volume, policy, iceberg);
+    allocated
+        .into_iter()
+        .enumerate()
+        .filter(|(idx, quantity)| {
+            *quantity > Q::ZERO && *quantity < orders[filled + idx].min_fill()
+        })
+        .map(|(idx, quantity)| (filled + idx, quantity))
+        .collect()
+}
+
+/// Number of orders at the price of the first one
 #[inline]
-fn aggregate_quantity() -> impl FnMut(&RegisteredOrder) -> (&RegisteredOrder, u64) {
-    let mut quantity: u64 = 0;
-    move |order: &RegisteredOrder| {
-        quantity += order.quantity as u64;
-        (order, quantity)
+fn price_level_len<P: PriceValue, Q: QuantityValue>(orders: &[RegisteredOrder<P, Q>]) -> usize {
+    match orders.first() {
+        Some(first) => orders
+            .iter()
+            .take_while(|order| order.rate == first.rate)
+            .count(),
+        None => 0,
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::orders::{Order, RegisteredOrder, RegisteredOrders};
+    use crate::orders::{FillCondition, Order, OrderType, RegisteredOrder, RegisteredOrders};
 
     fn test_order(
         registered: &mut RegisteredOrders,
@@ -177,11 +955,7 @@ mod tests {
         quantity: u32,
         order_type: OrderType,
     ) -> RegisteredOrder {
-        let order = Order {
-            order_type,
-            quantity,
-            rate,
-        };
+        let order = Order::limit(0, order_type, rate, quantity);
         registered.add_get_order(order, 0)
     }
 
@@ -195,8 +969,8 @@ mod tests {
             .collect();
         let mut buy_orders = SortedOrders::new(OrderType::Buy);
         let mut sell_orders = SortedOrders::new(OrderType::Sell);
-        buy_orders.add_remove_batch(&mut buy_samples, &orders);
-        sell_orders.add_remove_batch(&mut sell_samples, &orders);
+        buy_orders.add_batch(&mut buy_samples);
+        sell_orders.add_batch(&mut sell_samples);
         (buy_orders, sell_orders)
     }
 
@@ -206,9 +980,14 @@ mod tests {
         assert_eq!(bid_orders.first().unwrap().rate, 100);
         assert_eq!(ask_orders.first().unwrap().rate, 1);
 
-        let result = market_match(bid_orders, ask_orders);
-        assert_eq!(result.traded_rate, Some(51));
+        let result = market_match(bid_orders, ask_orders, None, &MatchConfig::default()).unwrap();
+        assert_eq!(result.traded_rate, Some(50));
         assert_eq!(result.traded_volume, 50);
+        assert_eq!(result.surplus, 1);
+        assert_eq!(
+            result.steps.last().map(|step| step.rule),
+            Some(ClearingRule::Midpoint)
+        );
 
         let (bids, asks): (Vec<_>, Vec<_>) = result
             .trades
@@ -230,9 +1009,10 @@ mod tests {
     fn market_match_result_big_quantity_buy_side() {
         let (bid_orders, ask_orders) = test_data(10, 1);
 
-        let result = market_match(bid_orders, ask_orders);
-        assert_eq!(result.traded_rate, Some(92));
-        assert_eq!(result.traded_volume, 90);
+        let result = market_match(bid_orders, ask_orders, None, &MatchConfig::default()).unwrap();
+        assert_eq!(result.traded_rate, Some(91));
+        assert_eq!(result.traded_volume, 91);
+        assert_eq!(result.open_bids.first().unwrap().quantity, 9);
         let (bids, asks): (Vec<_>, Vec<_>) = result
             .trades
             .iter()
@@ -253,9 +1033,10 @@ mod tests {
     fn market_match_result_big_quantity_sell_side() {
         let (bid_orders, ask_orders) = test_data(1, 10);
 
-        let result = market_match(bid_orders, ask_orders);
-        assert_eq!(result.traded_rate, Some(9));
-        assert_eq!(result.traded_volume, 90);
+        let result = market_match(bid_orders, ask_orders, None, &MatchConfig::default()).unwrap();
+        assert_eq!(result.traded_rate, Some(10));
+        assert_eq!(result.traded_volume, 91);
+        assert_eq!(result.open_asks.first().unwrap().quantity, 9);
 
         let (bids, asks): (Vec<_>, Vec<_>) = result
             .trades
@@ -272,4 +1053,439 @@ mod tests {
             asks.iter().map(|deal| deal.quantity).sum::<u32>()
         );
     }
+
+    fn books(orders: &[(OrderType, Price, u32)]) -> (SortedOrders, SortedOrders) {
+        let mut registered = RegisteredOrders::default();
+        let mut bids = SortedOrders::new(OrderType::Buy);
+        let mut asks = SortedOrders::new(OrderType::Sell);
+        let (mut buy_samples, mut sell_samples): (Vec<_>, Vec<_>) = orders
+            .iter()
+            .map(|(order_type, rate, quantity)| {
+                test_order(&mut registered, *rate, *quantity, *order_type)
+            })
+            .partition(|order| order.order_type == OrderType::Buy);
+        bids.add_batch(&mut buy_samples);
+        asks.add_batch(&mut sell_samples);
+        (bids, asks)
+    }
+
+    #[test]
+    fn market_match_maximum_volume() {
+        let (bids, asks) = books(&[
+            (OrderType::Buy, 100, 10),
+            (OrderType::Buy, 95, 10),
+            (OrderType::Sell, 90, 5),
+            (OrderType::Sell, 96, 10),
+        ]);
+        let result = market_match(bids, asks, None, &MatchConfig::default()).unwrap();
+        assert_eq!(result.traded_rate, Some(96));
+        assert_eq!(result.traded_volume, 10);
+        assert_eq!(result.bids_matched, 1);
+        assert_eq!(result.asks_matched, 2);
+        assert_eq!(result.open_bids.len(), 1);
+        assert_eq!(result.open_asks.first().unwrap().quantity, 5);
+        assert_eq!(result.steps[0].candidates, 2);
+        assert_eq!(
+            result.steps.last().map(|step| step.rule),
+            Some(ClearingRule::MarketPressure)
+        );
+    }
+
+    #[test]
+    fn indicative_match_leaves_books() {
+        let (bids, asks) = books(&[
+            (OrderType::Buy, 100, 10),
+            (OrderType::Buy, 95, 10),
+            (OrderType::Sell, 90, 5),
+            (OrderType::Sell, 96, 10),
+        ]);
+        let config = MatchConfig::default();
+        let indicative = indicative_match(&bids, &asks, None, &config).unwrap();
+        assert_eq!((bids.len(), asks.len()), (2, 2));
+        assert_eq!(indicative.rate, 96);
+        assert_eq!(indicative.volume, 10);
+        assert_eq!(indicative.imbalance(), -5);
+        assert_eq!(indicative.imbalance_side(), Some(OrderType::Sell));
+        assert_eq!(indicative.interruption, None);
+
+        let result = market_match(bids, asks, None, &config).unwrap();
+        assert_eq!(result.traded_rate, Some(indicative.rate));
+        assert_eq!(result.traded_volume, indicative.volume);
+        assert_eq!(result.surplus, indicative.imbalance());
+
+        let (bids, asks) = books(&[(OrderType::Buy, 90, 10), (OrderType::Sell, 100, 10)]);
+        assert!(matches!(
+            indicative_match(&bids, &asks, None, &config),
+            Err(MatchErrorKind::NoCross { .. })
+        ));
+    }
+
+    #[test]
+    fn market_match_market_pressure() {
+        let (bids, asks) = books(&[(OrderType::Buy, 100, 20), (OrderType::Sell, 90, 10)]);
+        let result = market_match(bids, asks, None, &MatchConfig::default()).unwrap();
+        assert_eq!(result.traded_rate, Some(100));
+        assert_eq!(result.surplus, 10);
+        assert_eq!(
+            result.steps.last().map(|step| step.rule),
+            Some(ClearingRule::MarketPressure)
+        );
+
+        let (bids, asks) = books(&[(OrderType::Buy, 100, 10), (OrderType::Sell, 90, 20)]);
+        let result = market_match(bids, asks, None, &MatchConfig::default()).unwrap();
+        assert_eq!(result.traded_rate, Some(90));
+        assert_eq!(result.surplus, -10);
+    }
+
+    #[test]
+    fn market_match_reference_price() {
+        let orders = [(OrderType::Buy, 100, 10), (OrderType::Sell, 90, 10)];
+        for (reference, expected) in &[(None, 95), (Some(93), 93), (Some(120), 100)] {
+            let (bids, asks) = books(&orders);
+            let result = market_match(bids, asks, *reference, &MatchConfig::default()).unwrap();
+            assert_eq!(result.traded_rate, Some(*expected));
+            assert_eq!(result.traded_volume, 10);
+            assert_eq!(result.steps.len(), 3);
+        }
+    }
+
+    #[test]
+    fn market_match_no_cross() {
+        let (bids, asks) = books(&[(OrderType::Buy, 90, 10), (OrderType::Sell, 100, 10)]);
+        let error = market_match(bids, asks, None, &MatchConfig::default()).unwrap_err();
+        assert_eq!(
+            error.kind,
+            MatchErrorKind::NoCross {
+                best_bid: 90,
+                best_ask: 100
+            }
+        );
+        assert_eq!(error.open_bids.len(), 1);
+        assert_eq!(error.open_asks.len(), 1);
+    }
+
+    #[test]
+    fn market_match_empty_books() {
+        let (bids, asks) = books(&[]);
+        let error = market_match(bids, asks, None, &MatchConfig::default()).unwrap_err();
+        assert_eq!(error.kind, MatchErrorKind::EmptyBook);
+
+        let (bids, asks) = books(&[(OrderType::Buy, 90, 10)]);
+        let error = market_match(bids, asks, None, &MatchConfig::default()).unwrap_err();
+        assert_eq!(error.kind, MatchErrorKind::OneSidedBook(OrderType::Buy));
+        assert_eq!(error.open_bids.len(), 1);
+
+        let (bids, asks) = books(&[(OrderType::Sell, 90, 10)]);
+        let error = market_match(bids, asks, None, &MatchConfig::default()).unwrap_err();
+        assert_eq!(error.kind, MatchErrorKind::OneSidedBook(OrderType::Sell));
+    }
+
+    fn market_books(
+        limit: &[(OrderType, Price, u32)],
+        market: &[(OrderType, u32)],
+    ) -> (SortedOrders, SortedOrders) {
+        let mut registered = RegisteredOrders::default();
+        let (mut bids, mut asks) = books(limit);
+        for (order_type, quantity) in market {
+            let order = registered.add_get_order(Order::market(0, *order_type, *quantity), 0);
+            match order_type {
+                OrderType::Buy => bids.add_batch(&mut vec![order]),
+                OrderType::Sell => asks.add_batch(&mut vec![order]),
+            }
+        }
+        (bids, asks)
+    }
+
+    #[test]
+    fn market_orders_priority() {
+        let (bids, asks) = market_books(
+            &[
+                (OrderType::Buy, 95, 10),
+                (OrderType::Sell, 90, 15),
+                (OrderType::Sell, 100, 10),
+            ],
+            &[(OrderType::Buy, 10)],
+        );
+        assert!(bids.first().unwrap().is_market());
+        let result = market_match(bids, asks, None, &MatchConfig::default()).unwrap();
+        assert_eq!(result.traded_rate, Some(95));
+        assert_eq!(result.traded_volume, 15);
+        assert_eq!(result.open_bids.len(), 1);
+        assert_eq!(result.open_bids.first().unwrap().rate, 95);
+        assert_eq!(result.open_bids.first().unwrap().quantity, 5);
+    }
+
+    #[test]
+    fn market_orders_only() {
+        let market = [(OrderType::Buy, 10), (OrderType::Sell, 5)];
+        let (bids, asks) = market_books(&[], &market);
+        let result = market_match(bids, asks, Some(100), &MatchConfig::default()).unwrap();
+        assert_eq!(result.traded_rate, Some(100));
+        assert_eq!(result.traded_volume, 5);
+        assert_eq!(result.open_bids.first().unwrap().quantity, 5);
+        assert!(result.open_asks.is_empty());
+
+        let (bids, asks) = market_books(&[], &market);
+        let error = market_match(bids, asks, None, &MatchConfig::default()).unwrap_err();
+        assert_eq!(error.kind, MatchErrorKind::NoLimitPrice);
+
+        let (bids, asks) = market_books(&[], &market);
+        let config = MatchConfig {
+            market_orders: MarketOrderClearing::NoTrade,
+            ..Default::default()
+        };
+        let error = market_match(bids, asks, Some(100), &config).unwrap_err();
+        assert_eq!(error.kind, MatchErrorKind::NoLimitPrice);
+        assert_eq!((error.open_bids.len(), error.open_asks.len()), (1, 1));
+
+        // Limit side alone sets the price
+        let (bids, asks) = market_books(
+            &[(OrderType::Sell, 90, 5), (OrderType::Sell, 100, 10)],
+            &[(OrderType::Buy, 10)],
+        );
+        let result = market_match(bids, asks, Some(50), &MatchConfig::default()).unwrap();
+        assert_eq!(result.traded_rate, Some(100));
+        assert_eq!(result.traded_volume, 10);
+    }
+
+    fn condition_books(
+        orders: &[(OrderType, Price, u32, FillCondition)],
+    ) -> (SortedOrders, SortedOrders) {
+        let mut registered = RegisteredOrders::default();
+        let mut bids = SortedOrders::new(OrderType::Buy);
+        let mut asks = SortedOrders::new(OrderType::Sell);
+        for (order_type, rate, quantity, condition) in orders {
+            let order = Order::limit(0, *order_type, *rate, *quantity).with_condition(*condition);
+            let order = registered.add_get_order(order, 0);
+            match order_type {
+                OrderType::Buy => bids.add_batch(&mut vec![order]),
+                OrderType::Sell => asks.add_batch(&mut vec![order]),
+            }
+        }
+        (bids, asks)
+    }
+
+    #[test]
+    fn all_or_none_changes_clearing() {
+        let (bids, asks) = condition_books(&[
+            (OrderType::Buy, 100, 10, FillCondition::Partial),
+            (OrderType::Buy, 95, 10, FillCondition::AllOrNone),
+            (OrderType::Sell, 90, 15, FillCondition::Partial),
+        ]);
+        let result = market_match(bids, asks, None, &MatchConfig::default()).unwrap();
+        assert_eq!(result.traded_rate, Some(90));
+        assert_eq!(result.traded_volume, 10);
+        assert_eq!(result.skipped.len(), 1);
+        assert_eq!(result.skipped[0].order.rate, 95);
+        assert_eq!(result.skipped[0].rate, 95);
+        assert_eq!(result.skipped[0].executable, 5);
+        assert_eq!(result.open_bids.len(), 1);
+        assert_eq!(result.open_bids.first().unwrap().quantity, 10);
+        assert_eq!(result.open_asks.first().unwrap().quantity, 5);
+    }
+
+    #[test]
+    fn minimum_quantity() {
+        let (bids, asks) = condition_books(&[
+            (OrderType::Buy, 100, 10, FillCondition::Partial),
+            (OrderType::Sell, 90, 20, FillCondition::MinQuantity(8)),
+        ]);
+        let result = market_match(bids, asks, None, &MatchConfig::default()).unwrap();
+        assert_eq!(result.traded_volume, 10);
+        assert!(result.skipped.is_empty());
+        assert_eq!(result.open_asks.first().unwrap().quantity, 10);
+
+        let (bids, asks) = condition_books(&[
+            (OrderType::Buy, 100, 5, FillCondition::Partial),
+            (OrderType::Sell, 90, 20, FillCondition::MinQuantity(8)),
+        ]);
+        let error = market_match(bids, asks, None, &MatchConfig::default()).unwrap_err();
+        // Held ask leaves nothing to trade, error still tells why
+        assert_eq!(error.skipped.len(), 1);
+        assert_eq!(error.skipped[0].order.order_type, OrderType::Sell);
+        assert_eq!(error.skipped[0].executable, 5);
+        assert_eq!((error.open_bids.len(), error.open_asks.len()), (1, 1));
+    }
+
+    #[test]
+    fn self_trade_prevention_modes() {
+        let mut registered = RegisteredOrders::default();
+        let mut add = |account, order_type, rate, quantity| {
+            registered.add_get_order(Order::limit(account, order_type, rate, quantity), 0)
+        };
+        // Account 1 bid arrives first, then its crossing asks
+        let orders = [
+            add(1, OrderType::Buy, 100, 10),
+            add(2, OrderType::Buy, 95, 10),
+            add(1, OrderType::Sell, 90, 4),
+            add(1, OrderType::Sell, 92, 4),
+            add(2, OrderType::Sell, 99, 10),
+            add(1, OrderType::Sell, 101, 10),
+        ];
+        let ids: Vec<_> = orders.iter().map(|order| order.id).collect();
+        for (mode, expected) in &[
+            (SelfTradePrevention::Allow, vec![]),
+            (SelfTradePrevention::CancelNewest, vec![(2, 4), (3, 4)]),
+            (SelfTradePrevention::CancelOldest, vec![(0, 10)]),
+            (SelfTradePrevention::CancelBoth, vec![(0, 10), (2, 4)]),
+            (
+                SelfTradePrevention::DecrementAndCancel,
+                vec![(0, 8), (2, 4), (3, 4)],
+            ),
+        ] {
+            let mut bids = SortedOrders::new(OrderType::Buy);
+            let mut asks = SortedOrders::new(OrderType::Sell);
+            let (mut buy_samples, mut sell_samples): (Vec<_>, Vec<_>) = orders
+                .iter()
+                .cloned()
+                .partition(|order| order.order_type == OrderType::Buy);
+            bids.add_batch(&mut buy_samples);
+            asks.add_batch(&mut sell_samples);
+            let config = MatchConfig {
+                self_trade_prevention: *mode,
+                ..Default::default()
+            };
+            let result = market_match(bids, asks, None, &config).unwrap();
+            let mut self_trades: Vec<_> = result
+                .self_trades
+                .iter()
+                .map(|self_trade| {
+                    let idx = ids.iter().position(|id| *id == self_trade.order.id);
+                    (idx.unwrap(), self_trade.reduced)
+                })
+                .collect();
+            self_trades.sort_unstable();
+            assert_eq!(&self_trades, expected, "{:?}", mode);
+            if *mode != SelfTradePrevention::Allow {
+                let own_sides: Vec<_> = result
+                    .trades
+                    .iter()
+                    .filter(|trade| trade.order.account == 1)
+                    .map(|trade| trade.order.order_type)
+                    .collect();
+                assert!(own_sides.windows(2).all(|pair| pair[0] == pair[1]));
+            }
+        }
+    }
+
+    #[test]
+    fn market_match_keeps_orders_behind_crossing() {
+        let mut registered = RegisteredOrders::default();
+        let mut add = |account, order_type, rate, quantity| {
+            registered.add_get_order(Order::limit(account, order_type, rate, quantity), 0)
+        };
+        let mut bids = SortedOrders::new(OrderType::Buy);
+        let mut asks = SortedOrders::new(OrderType::Sell);
+        bids.add_batch(&mut vec![
+            add(1, OrderType::Buy, 100, 10),
+            add(2, OrderType::Buy, 90, 5),
+            add(3, OrderType::Buy, 80, 5),
+        ]);
+        asks.add_batch(&mut vec![
+            add(1, OrderType::Sell, 95, 10),
+            add(2, OrderType::Sell, 110, 5),
+        ]);
+        let config = MatchConfig {
+            self_trade_prevention: SelfTradePrevention::CancelBoth,
+            ..Default::default()
+        };
+        // Only crossing orders cancel, auction then sees the best orders behind them
+        let error = market_match(bids, asks, None, &config).unwrap_err();
+        assert_eq!(
+            error.kind,
+            MatchErrorKind::NoCross {
+                best_bid: 90,
+                best_ask: 110
+            }
+        );
+        let rates = |book: &SortedOrders| book.iter().map(|order| order.rate).collect::<Vec<_>>();
+        assert_eq!(rates(&error.open_bids), vec![90, 80]);
+        assert_eq!(rates(&error.open_asks), vec![110]);
+    }
+
+    #[test]
+    fn market_match_leaves_unexecuted_levels() {
+        let (bids, asks) = books(&[
+            (OrderType::Buy, 100, 10),
+            (OrderType::Buy, 99, 10),
+            (OrderType::Buy, 98, 10),
+            (OrderType::Sell, 90, 5),
+        ]);
+        // Crossing bids behind the executed level stay in the book untouched
+        let result = market_match(bids, asks, None, &MatchConfig::default()).unwrap();
+        assert_eq!(result.traded_volume, 5);
+        let open: Vec<_> = result
+            .open_bids
+            .iter()
+            .map(|order| (order.rate, order.quantity))
+            .collect();
+        assert_eq!(open, vec![(100, 5), (99, 10), (98, 10)]);
+        assert!(result.open_asks.is_empty());
+    }
+
+    #[test]
+    fn market_match_marginal_level_allocation() {
+        for (allocation, supply, expected) in &[
+            (AllocationPolicy::ProRata, 15, [5, 10]),
+            (AllocationPolicy::EqualSplit, 16, [8, 8]),
+        ] {
+            let (bids, asks) = books(&[
+                (OrderType::Buy, 100, 10),
+                (OrderType::Buy, 100, 20),
+                (OrderType::Sell, 90, *supply),
+            ]);
+            let config = MatchConfig {
+                allocation: *allocation,
+                ..Default::default()
+            };
+            let result = market_match(bids, asks, None, &config).unwrap();
+            assert_eq!(result.traded_rate, Some(100));
+            assert_eq!(result.bids_matched, 2);
+            assert_eq!(result.open_bids.len(), 2);
+            for trade in result.trades.iter() {
+                match (trade.order.order_type, trade.order.quantity) {
+                    (OrderType::Buy, 10) => assert_eq!(trade.quantity, expected[0]),
+                    (OrderType::Buy, _) => assert_eq!(trade.quantity, expected[1]),
+                    (OrderType::Sell, _) => assert_eq!(trade.quantity, *supply),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn market_match_wide_fixed_point() {
+        let spec: InstrumentSpec<i64, u64> = InstrumentSpec {
+            price_scale: 8,
+            quantity_scale: 6,
+            ..Default::default()
+        };
+        let mut registered = RegisteredOrders::default();
+        let mut add = |order_type, rate, quantity| {
+            let rate = spec.parse_price(rate).unwrap();
+            let quantity = spec.parse_quantity(quantity).unwrap();
+            let order = Order::new(0, order_type, OrderKind::Limit, rate, quantity);
+            registered.add_get_order(order, 0)
+        };
+        let mut buy_samples = vec![add(OrderType::Buy, "64250.5", "5000000.25")];
+        let mut sell_samples = vec![add(OrderType::Sell, "64249.50000001", "5000000")];
+        let mut bids = SortedOrders::new(OrderType::Buy);
+        let mut asks = SortedOrders::new(OrderType::Sell);
+        bids.add_batch(&mut buy_samples);
+        asks.add_batch(&mut sell_samples);
+
+        let config = MatchConfig {
+            spec,
+            ..Default::default()
+        };
+        let result = market_match(bids, asks, None, &config).unwrap();
+        assert_eq!(result.traded_volume, 5_000_000_000_000);
+        assert_eq!(
+            spec.format_price(result.traded_rate.unwrap()),
+            "64250.50000000"
+        );
+        assert_eq!(
+            spec.format_quantity(result.open_bids.first().unwrap().quantity),
+            "0.250000"
+        );
+    }
 }
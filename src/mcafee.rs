@@ -0,0 +1,404 @@
+//! McAfee dominant strategy truthful double auction.
+//! Each order is treated as `quantity` unit traders with the same limit price:
+//! -- Find efficient trade count K, where K-th highest bid still covers K-th lowest ask
+//! -- Price candidate p0 is midpoint of (K+1)-th bid and ask
+//! -- If p0 fits within K-th ask and bid all K units trade at p0
+//! -- Otherwise K-1 units trade, buyers pay K-th bid, sellers receive K-th ask,
+//!    auctioneer keeps the difference
+//!
+//! Crossing orders of the same account are resolved first by configured self trade prevention.
+//! Orders whose fill condition can not be met by the traded units are left out
+//! and the trade count is found again without them.
+//! Price paid by buyers or received by sellers outside of configured price collar
+//! interrupts the auction, dynamic band is centred on `reference_price`.
+
+use crate::{
+    allocation::{AllocationPolicy::PriceTime, IcebergAllocation::VisibleFirst},
+    book::OrderBookSide,
+    fixed::{PriceValue, QuantityValue},
+    market::{
+        add_volume, best_prices, execute, prevent_self_trades, put_back, restore, take_crossing,
+        unmet_conditions, volume_while, ClearingRule, ClearingStep, MarketMatchResult, MatchConfig,
+        MatchError, MatchErrorKind, SkippedOrder,
+    },
+    orders::{OrderKind, RegisteredOrder},
+};
+
+/// Efficient trade count with marginal prices
+struct EfficientTrade<P> {
+    units: u64,
+    /// Limit prices of K-th bid and ask
+    bid: P,
+    ask: P,
+    /// Limit prices of (K+1)-th bid and ask if any
+    next_bid: Option<P>,
+    next_ask: Option<P>,
+}
+
+/// Match books with trade reduction, `traded_rate` of result is the price paid by buyers,
+/// price received by sellers is given in their trades
+pub fn mcafee_match<P: PriceValue, Q: QuantityValue, B: OrderBookSide<P, Q>>(
+    mut open_bids: B,
+    mut open_asks: B,
+    reference_price: Option<P>,
+    config: &MatchConfig<P, Q>,
+) -> Result<MarketMatchResult<P, Q, B>, MatchError<P, Q, B>> {
+    let (best_bid, best_ask) = match best_prices(open_bids.first(), open_asks.first()) {
+        Ok(best) => best,
+        Err(kind) => return Err(MatchError::new(kind, open_bids, open_asks)),
+    };
+    // Units walked never exceed total of crossing orders of either side
+    let overflow = open_bids
+        .iter()
+        .take_while(|order| order.rate >= best_ask)
+        .try_fold(0, add_volume)
+        .and_then(|_| {
+            open_asks
+                .iter()
+                .take_while(|order| order.rate <= best_bid)
+                .try_fold(0, add_volume)
+        });
+    if let Err(kind) = overflow {
+        return Err(MatchError::new(kind, open_bids, open_asks));
+    }
+    // Market orders sort first and have no limit price to reduce trade by
+    if open_bids.first().is_some_and(RegisteredOrder::is_market)
+        || open_asks.first().is_some_and(RegisteredOrder::is_market)
+    {
+        let kind = MatchErrorKind::UnsupportedOrderKind(OrderKind::Market);
+        return Err(MatchError::new(kind, open_bids, open_asks));
+    }
+    // Walk stops at the latest on the first order behind crossing ones
+    let mut bids = take_crossing(&mut open_bids, |rate| rate >= best_ask);
+    let mut asks = take_crossing(&mut open_asks, |rate| rate <= best_bid);
+    let self_trades = prevent_self_trades(&mut bids, &mut asks, config.self_trade_prevention);
+    let mut skipped = Vec::new();
+    let (mut held_bids, mut held_asks) = (Vec::new(), Vec::new());
+    let cleared = loop {
+        // Books might not cross any more without cancelled or held orders
+        if let Err(kind) = best_prices(bids.first(), asks.first()) {
+            break Err(kind);
+        }
+        let (bid_rate, ask_rate, volume, step) = reduced_trade(efficient_trade(&bids, &asks));
+        let unmet_bids = unmet_conditions(&bids, volume, PriceTime, VisibleFirst);
+        let unmet_asks = unmet_conditions(&asks, volume, PriceTime, VisibleFirst);
+        if unmet_bids.is_empty() && unmet_asks.is_empty() {
+            let interruption = config
+                .collar
+                .check(bid_rate, reference_price)
+                .or_else(|| config.collar.check(ask_rate, reference_price));
+            break match interruption {
+                Some(interruption) => Err(MatchErrorKind::VolatilityInterruption(interruption)),
+                None => Ok((bid_rate, ask_rate, volume, step)),
+            };
+        }
+        for (orders, held, unmet, rate) in [
+            (&mut bids, &mut held_bids, unmet_bids, bid_rate),
+            (&mut asks, &mut held_asks, unmet_asks, ask_rate),
+        ] {
+            for (idx, executable) in unmet.into_iter().rev() {
+                let order = orders.remove(idx);
+                skipped.push(SkippedOrder {
+                    order: order.clone(),
+                    rate,
+                    executable,
+                });
+                held.push(order);
+            }
+        }
+    };
+    let (bid_rate, ask_rate, volume, step) = match cleared {
+        Ok(cleared) => cleared,
+        Err(kind) => {
+            put_back(&mut open_bids, bids);
+            put_back(&mut open_asks, asks);
+            restore(&mut open_bids, &mut held_bids);
+            restore(&mut open_asks, &mut held_asks);
+            let mut error = MatchError::new(kind, open_bids, open_asks);
+            error.self_trades = self_trades;
+            error.skipped = skipped;
+            return Err(error);
+        }
+    };
+    let demand = volume_while(&bids, |order| order.rate >= bid_rate);
+    let supply = volume_while(&asks, |order| order.rate <= ask_rate);
+
+    let mut trades = Vec::new();
+    let bids_matched = execute(
+        &mut bids,
+        volume,
+        bid_rate,
+        PriceTime,
+        VisibleFirst,
+        &mut trades,
+    );
+    let asks_matched = execute(
+        &mut asks,
+        volume,
+        ask_rate,
+        PriceTime,
+        VisibleFirst,
+        &mut trades,
+    );
+    put_back(&mut open_bids, bids);
+    put_back(&mut open_asks, asks);
+    restore(&mut open_bids, &mut held_bids);
+    restore(&mut open_asks, &mut held_asks);
+    Ok(MarketMatchResult {
+        open_bids,
+        open_asks,
+        trades,
+        traded_volume: volume,
+        traded_rate: if volume > 0 { Some(bid_rate) } else { None },
+        bids_matched,
+        asks_matched,
+        surplus: demand as i64 - supply as i64,
+        steps: vec![step],
+        // Volume fits i64 and spread fits u64, so their product fits u128
+        budget_surplus: volume as u128
+            * (bid_rate.to_raw() as i128 - ask_rate.to_raw() as i128) as u128,
+        self_trades,
+        skipped,
+    })
+}
+
+/// Buyer and seller prices with traded units: all K units at p0 when it fits
+/// within K-th ask and bid, otherwise K-1 units at K-th bid and ask
+fn reduced_trade<P: PriceValue>(efficient: EfficientTrade<P>) -> (P, P, u64, ClearingStep<P>) {
+    let p0 = match (efficient.next_bid, efficient.next_ask) {
+        (Some(bid), Some(ask)) => Some(ask.midpoint(bid)),
+        _ => None,
+    };
+    match p0 {
+        Some(rate) if efficient.ask <= rate && rate <= efficient.bid => (
+            rate,
+            rate,
+            efficient.units,
+            ClearingStep::single(ClearingRule::Midpoint, rate),
+        ),
+        _ => (
+            efficient.bid,
+            efficient.ask,
+            efficient.units.saturating_sub(1),
+            ClearingStep {
+                rule: ClearingRule::TradeReduction,
+                lowest: efficient.ask,
+                highest: efficient.bid,
+                candidates: 2,
+            },
+        ),
+    }
+}
+
+/// Walk both books unit by unit while bid covers ask, books must cross
+fn efficient_trade<P: PriceValue, Q: QuantityValue>(
+    bids: &[RegisteredOrder<P, Q>],
+    asks: &[RegisteredOrder<P, Q>],
+) -> EfficientTrade<P> {
+    let (mut bid_idx, mut ask_idx) = (0, 0);
+    let mut bid_left = bids[0].quantity.to_raw();
+    let mut ask_left = asks[0].quantity.to_raw();
+    let mut efficient = EfficientTrade {
+        units: 0,
+        bid: bids[0].rate,
+        ask: asks[0].rate,
+        next_bid: None,
+        next_ask: None,
+    };
+    while bid_idx < bids.len() && ask_idx < asks.len() {
+        let (bid, ask) = (&bids[bid_idx], &asks[ask_idx]);
+        if bid.rate < ask.rate {
+            break;
+        }
+        let units = bid_left.min(ask_left);
+        efficient.units += units;
+        efficient.bid = bid.rate;
+        efficient.ask = ask.rate;
+        bid_left -= units;
+        ask_left -= units;
+        if bid_left == 0 {
+            bid_idx += 1;
+            bid_left = bids.get(bid_idx).map_or(0, |order| order.quantity.to_raw());
+        }
+        if ask_left == 0 {
+            ask_idx += 1;
+            ask_left = asks.get(ask_idx).map_or(0, |order| order.quantity.to_raw());
+        }
+    }
+    efficient.next_bid = bids.get(bid_idx).map(|order| order.rate);
+    efficient.next_ask = asks.get(ask_idx).map(|order| order.rate);
+    efficient
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        collar::{CollarBand, PriceCollar},
+        market::SelfTradePrevention,
+        orders::{FillCondition, Order, OrderType, Price, RegisteredOrders, TimeInForce},
+        sorted_vec_orders::SortedOrders,
+    };
+
+    fn books(bids: &[(Price, u32)], asks: &[(Price, u32)]) -> (SortedOrders, SortedOrders) {
+        let mut registered = RegisteredOrders::default();
+        let mut samples = |order_type, orders: &[(Price, u32)]| {
+            let mut book = SortedOrders::new(order_type);
+            let mut samples: Vec<_> = orders
+                .iter()
+                .map(|(rate, quantity)| {
+                    let order = Order::limit(0, order_type, *rate, *quantity);
+                    registered.add_get_order(order, 0)
+                })
+                .collect();
+            book.add_batch(&mut samples);
+            book
+        };
+        (
+            samples(OrderType::Buy, bids),
+            samples(OrderType::Sell, asks),
+        )
+    }
+
+    #[test]
+    fn mcafee_uniform_price() {
+        let (bids, asks) = books(
+            &[(10, 1), (9, 1), (8, 1), (4, 1)],
+            &[(1, 1), (2, 1), (3, 1), (6, 1)],
+        );
+        let result = mcafee_match(bids, asks, None, &MatchConfig::default()).unwrap();
+        assert_eq!(result.traded_volume, 3);
+        assert_eq!(result.traded_rate, Some(5));
+        assert_eq!(result.budget_surplus, 0);
+        assert!(result.trades.iter().all(|trade| trade.rate == 5));
+        assert_eq!(result.open_bids.len(), 1);
+        assert_eq!(result.open_asks.len(), 1);
+    }
+
+    #[test]
+    fn mcafee_trade_reduction() {
+        let (bids, asks) = books(
+            &[(10, 1), (9, 1), (8, 1), (7, 1)],
+            &[(1, 1), (2, 1), (3, 1), (11, 1)],
+        );
+        let result = mcafee_match(bids, asks, None, &MatchConfig::default()).unwrap();
+        assert_eq!(result.traded_volume, 2);
+        assert_eq!(result.traded_rate, Some(8));
+        assert_eq!(result.budget_surplus, 10);
+        assert_eq!(result.steps[0].rule, ClearingRule::TradeReduction);
+        for trade in result.trades.iter() {
+            match trade.order.order_type {
+                OrderType::Buy => assert_eq!(trade.rate, 8),
+                OrderType::Sell => assert_eq!(trade.rate, 3),
+            }
+        }
+        assert_eq!(result.open_bids.len(), 2);
+        assert_eq!(result.open_asks.len(), 2);
+    }
+
+    #[test]
+    fn mcafee_price_collar() {
+        let (bids, asks) = books(
+            &[(10, 1), (9, 1), (8, 1), (7, 1)],
+            &[(1, 1), (2, 1), (3, 1), (11, 1)],
+        );
+        let config = MatchConfig {
+            collar: PriceCollar {
+                dynamic_tolerance: Some(5_000),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        // Buyers pay 8 within the band, sellers receive 3 below it
+        let error = mcafee_match(bids, asks, Some(8), &config).unwrap_err();
+        match error.kind {
+            MatchErrorKind::VolatilityInterruption(interruption) => {
+                assert_eq!(interruption.rate, 3);
+                assert_eq!(interruption.band, CollarBand::Dynamic);
+                assert_eq!((interruption.lowest, interruption.highest), (4, 12));
+            }
+            kind => panic!("Unexpected {:?}", kind),
+        }
+        assert_eq!((error.open_bids.len(), error.open_asks.len()), (4, 4));
+    }
+
+    #[test]
+    fn mcafee_multi_unit_orders() {
+        let (bids, asks) = books(&[(10, 3)], &[(1, 2), (2, 2)]);
+        let result = mcafee_match(bids, asks, None, &MatchConfig::default()).unwrap();
+        assert_eq!(result.traded_volume, 2);
+        assert_eq!(result.budget_surplus, 16);
+        assert_eq!(result.open_bids.first().unwrap().quantity, 1);
+        assert_eq!(result.open_asks.len(), 1);
+    }
+
+    #[test]
+    fn mcafee_prevents_self_trades() {
+        let mut registered = RegisteredOrders::default();
+        let mut bids = SortedOrders::new(OrderType::Buy);
+        let mut asks = SortedOrders::new(OrderType::Sell);
+        let mut add = |book: &mut SortedOrders, account, order_type, rate, quantity| {
+            let order = Order::limit(account, order_type, rate, quantity);
+            book.add_batch(&mut vec![registered.add_get_order(order, 0)]);
+        };
+        add(&mut bids, 1, OrderType::Buy, 10, 1);
+        add(&mut bids, 2, OrderType::Buy, 9, 1);
+        add(&mut asks, 1, OrderType::Sell, 1, 1);
+        add(&mut asks, 3, OrderType::Sell, 2, 1);
+        let config = MatchConfig {
+            self_trade_prevention: SelfTradePrevention::CancelBoth,
+            ..Default::default()
+        };
+        let result = mcafee_match(bids, asks, None, &config).unwrap();
+        assert_eq!(result.self_trades.len(), 2);
+        assert!(result.trades.iter().all(|trade| trade.order.account != 1));
+
+        // Nothing crosses without the cancelled orders
+        let mut bids = SortedOrders::new(OrderType::Buy);
+        let mut asks = SortedOrders::new(OrderType::Sell);
+        add(&mut bids, 1, OrderType::Buy, 10, 1);
+        add(&mut asks, 1, OrderType::Sell, 1, 1);
+        let error = mcafee_match(bids, asks, None, &config).unwrap_err();
+        assert_eq!(error.kind, MatchErrorKind::EmptyBook);
+        assert_eq!(error.self_trades.len(), 2);
+    }
+
+    #[test]
+    fn mcafee_all_or_none() {
+        let mut registered = RegisteredOrders::default();
+        let mut bids = SortedOrders::new(OrderType::Buy);
+        let (_, asks) = books(&[], &[(1, 2), (2, 2)]);
+        let all_or_none =
+            Order::limit(0, OrderType::Buy, 10, 4).with_condition(FillCondition::AllOrNone);
+        let mut buy_samples = vec![
+            registered.add_get_order(all_or_none, 0),
+            registered.add_get_order(Order::limit(0, OrderType::Buy, 9, 2), 0),
+        ];
+        bids.add_batch(&mut buy_samples);
+        // Trade reduction leaves 3 of 4 units, held order changes the trade count
+        let result = mcafee_match(bids, asks, None, &MatchConfig::default()).unwrap();
+        assert_eq!(result.skipped.len(), 1);
+        assert_eq!(result.skipped[0].rate, 10);
+        assert_eq!(result.skipped[0].executable, 3);
+        assert_eq!(result.traded_volume, 1);
+        assert_eq!(result.traded_rate, Some(9));
+        assert_eq!(result.open_bids.len(), 2);
+        assert_eq!(result.open_bids.first().unwrap().quantity, 4);
+        let asks = result.open_asks;
+        assert_eq!(asks.len(), 2);
+
+        // Fill-or-kill order is never filled partially either
+        let mut bids = SortedOrders::new(OrderType::Buy);
+        let fill_or_kill =
+            Order::limit(0, OrderType::Buy, 10, 4).with_time_in_force(TimeInForce::FillOrKill);
+        bids.add_batch(&mut vec![registered.add_get_order(fill_or_kill, 0)]);
+        let error = mcafee_match(bids, asks, None, &MatchConfig::default()).unwrap_err();
+        assert_eq!(error.skipped.len(), 1);
+        assert_eq!(
+            error.skipped[0].order.time_in_force,
+            TimeInForce::FillOrKill
+        );
+        assert_eq!(error.skipped[0].executable, 2);
+        assert_eq!(error.open_bids.first().unwrap().quantity, 4);
+    }
+}
@@ -1,9 +1,23 @@
+use crate::fixed::{PriceValue, QuantityValue};
 use nanorand::{WyRand, RNG};
-use slotmap::HopSlotMap;
-use std::ops::{Deref, DerefMut};
+use slotmap::{Key, KeyData, SecondaryMap, SparseSecondaryMap};
+use std::{
+    collections::HashMap,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
 
+/// Default price type, see `fixed` for wider ones
 pub type Price = i32;
-pub type Epoch = u16;
+/// Default quantity type, see `fixed` for wider ones
+pub type Quantity = u32;
+/// Auction counter, wide enough not to wrap over lifetime of an engine
+pub type Epoch = u64;
+pub type AccountId = u32;
+pub type InstrumentId = u32;
 
 slotmap::new_key_type! {
     pub struct OrderId;
@@ -15,32 +29,177 @@ pub enum OrderType {
     Sell,
 }
 
-#[derive(Debug, Clone)]
-pub struct Order {
+/// Price condition of the order
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OrderKind<P = Price> {
+    /// Executes at clearing price not worse than order rate
+    Limit,
+    /// Executes at any clearing price, rate is set to the most aggressive price of the side
+    Market,
+    /// Dormant until traded rate reaches trigger price, then becomes market order
+    Stop { trigger: P },
+    /// Dormant until traded rate reaches trigger price, then becomes limit order
+    StopLimit { trigger: P },
+}
+
+/// Restriction on partial execution of the order
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FillCondition<Q = Quantity> {
+    /// Any quantity might be executed
+    Partial,
+    /// Order executes in full or not at all
+    AllOrNone,
+    /// Order executes at least given quantity or its whole remaining quantity if less
+    MinQuantity(Q),
+}
+
+/// How long order stays in the book
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeInForce {
+    /// Order stays until filled or cancelled
+    GoodTillCancel,
+    /// Order takes part in the next auction only, remaining quantity is cancelled
+    GoodForAuction,
+    /// Order is filled in full in the next auction or cancelled
+    FillOrKill,
+    /// Order takes part in auctions up to and including given epoch
+    GoodTillEpoch(Epoch),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Order<P = Price, Q = Quantity> {
+    pub instrument: InstrumentId,
+    pub account: AccountId,
+    pub kind: OrderKind<P>,
+    pub condition: FillCondition<Q>,
+    pub time_in_force: TimeInForce,
+    /// Displayed quantity of iceberg order, None if whole quantity is displayed
+    pub peak: Option<Q>,
     pub order_type: OrderType,
-    pub rate: Price,
-    pub quantity: u32,
+    pub rate: P,
+    pub quantity: Q,
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub struct RegisteredOrder {
+pub struct RegisteredOrder<P = Price, Q = Quantity> {
     pub id: OrderId,
     pub epoch: Epoch,
+    /// Arrival sequence, orders registered or requeued later get higher number
+    pub sequence: u64,
+    pub instrument: InstrumentId,
+    pub account: AccountId,
+    pub kind: OrderKind<P>,
+    pub condition: FillCondition<Q>,
+    pub time_in_force: TimeInForce,
+    /// Displayed quantity of iceberg order, None if whole quantity is displayed
+    pub peak: Option<Q>,
     pub order_type: OrderType,
-    pub rate: Price,
-    pub quantity: u32,
+    pub rate: P,
+    /// Total remaining quantity, displayed and hidden
+    pub quantity: Q,
+    /// Remaining quantity of displayed tranche
+    pub displayed: Q,
+}
+
+/// Key space of order ids, registries sharing it never give out the same id.
+/// Each registry reuses slots of its own orders, key space only hands out versions,
+/// so every id carries a version no other registry has given out.
+#[derive(Debug, Clone, Default)]
+pub struct OrderIds(Arc<AtomicU32>);
+
+impl OrderIds {
+    /// Odd version as slot map expects, it repeats only after 2^31 ids
+    #[inline]
+    fn next_version(&self) -> u32 {
+        self.0.fetch_add(2, Ordering::Relaxed) | 1
+    }
 }
 
 #[derive(Default)]
-pub struct RegisteredOrders {
-    orders: HopSlotMap<OrderId, RegisteredOrder>,
+pub struct RegisteredOrders<P = Price, Q = Quantity> {
+    /// Indexed by slot of order id, slots of removed orders are reused
+    orders: SecondaryMap<OrderId, RegisteredOrder<P, Q>>,
+    /// Where versions of new order ids come from
+    ids: OrderIds,
+    /// Slots of removed orders
+    free: Vec<u32>,
+    /// Number of slots ever taken
+    slots: u32,
+    /// Orders fully filled since last call to `clear_filled`
+    filled: SparseSecondaryMap<OrderId, ()>,
+    /// Last assigned arrival sequence
+    sequence: u64,
+    /// Live orders of every account
+    accounts: HashMap<AccountId, SparseSecondaryMap<OrderId, ()>>,
+}
+
+/// Outcome of an order amend request
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModifyResult<P = Price, Q = Quantity> {
+    /// Quantity reduced, order keeps its place in the book
+    Reduced(RegisteredOrder<P, Q>),
+    /// Price changed or quantity increased, order is queued again as of amend epoch
+    Requeued(RegisteredOrder<P, Q>),
+    /// No such order was ever registered or it was cancelled
+    UnknownOrder,
+    /// Order was fully filled in the last auction
+    AlreadyFilled,
+    /// Amend would change order instrument, kind, fill condition, time in force, peak,
+    /// side or owner, market order rate, or set zero quantity
+    Invalid,
 }
 
 impl Order {
+    /// Limit order on default instrument 0
+    #[inline]
+    pub fn limit(account: AccountId, order_type: OrderType, rate: Price, quantity: u32) -> Self {
+        Self::new(account, order_type, OrderKind::Limit, rate, quantity)
+    }
+
+    /// Market order on default instrument 0
+    #[inline]
+    pub fn market(account: AccountId, order_type: OrderType, quantity: u32) -> Self {
+        Self::new(
+            account,
+            order_type,
+            OrderKind::Market,
+            market_rate(order_type),
+            quantity,
+        )
+    }
+
+    /// Stop order on default instrument 0, becomes market order once triggered
+    #[inline]
+    pub fn stop(account: AccountId, order_type: OrderType, trigger: Price, quantity: u32) -> Self {
+        let mut order = Self::market(account, order_type, quantity);
+        order.kind = OrderKind::Stop { trigger };
+        order
+    }
+
+    /// Stop limit order on default instrument 0, becomes limit order once triggered
+    #[inline]
+    pub fn stop_limit(
+        account: AccountId,
+        order_type: OrderType,
+        trigger: Price,
+        rate: Price,
+        quantity: u32,
+    ) -> Self {
+        let mut order = Self::limit(account, order_type, rate, quantity);
+        order.kind = OrderKind::StopLimit { trigger };
+        order
+    }
+
     pub fn random(rng: &mut WyRand, prices_min: u32, prices_max: u32, buy_sell_dev: i32) -> Self {
         let buy: bool = rng.generate();
         let price = (rng.generate::<u32>() % (prices_max - prices_min) + prices_min) as i32;
         Self {
+            instrument: 0,
+            account: rng.generate_range(0, 1000),
+            kind: OrderKind::Limit,
+            condition: FillCondition::Partial,
+            time_in_force: TimeInForce::GoodTillCancel,
+            peak: None,
             order_type: if buy { OrderType::Buy } else { OrderType::Sell },
             rate: if buy {
                 price - buy_sell_dev / 2
@@ -52,58 +211,475 @@ impl Order {
     }
 }
 
-impl RegisteredOrder {
+impl<P: PriceValue, Q: QuantityValue> Order<P, Q> {
+    /// Order of any kind on default instrument 0, `rate` of market orders is `market_rate`
+    #[inline]
+    pub fn new(
+        account: AccountId,
+        order_type: OrderType,
+        kind: OrderKind<P>,
+        rate: P,
+        quantity: Q,
+    ) -> Self {
+        Self {
+            instrument: 0,
+            account,
+            kind,
+            condition: FillCondition::Partial,
+            time_in_force: TimeInForce::GoodTillCancel,
+            peak: None,
+            order_type,
+            rate,
+            quantity,
+        }
+    }
+
+    #[inline]
+    pub fn on_instrument(mut self, instrument: InstrumentId) -> Self {
+        self.instrument = instrument;
+        self
+    }
+
+    #[inline]
+    pub fn with_condition(mut self, condition: FillCondition<Q>) -> Self {
+        self.condition = condition;
+        self
+    }
+
+    /// Iceberg order displaying at most `peak` of its quantity
+    #[inline]
+    pub fn with_peak(mut self, peak: Q) -> Self {
+        self.peak = Some(peak);
+        self
+    }
+
+    #[inline]
+    pub fn with_time_in_force(mut self, time_in_force: TimeInForce) -> Self {
+        self.time_in_force = time_in_force;
+        self
+    }
+}
+
+impl<P> OrderKind<P> {
+    /// Rate of the order is set by its side, not by trader
+    #[inline]
+    pub fn has_market_rate(&self) -> bool {
+        matches!(self, OrderKind::Market | OrderKind::Stop { .. })
+    }
+}
+
+/// Displayed quantity of order, iceberg shows at least one unit
+#[inline]
+fn tranche<Q: QuantityValue>(peak: Option<Q>, quantity: Q) -> Q {
+    peak.map_or(quantity, |peak| peak.max(Q::ONE).min(quantity))
+}
+
+/// Rate of market order, it crosses any limit price of the opposite side
+#[inline]
+pub fn market_rate<P: PriceValue>(order_type: OrderType) -> P {
+    match order_type {
+        OrderType::Buy => P::MAX,
+        OrderType::Sell => P::MIN,
+    }
+}
+
+impl<P: PriceValue, Q: QuantityValue> RegisteredOrder<P, Q> {
+    #[inline]
+    pub fn is_market(&self) -> bool {
+        self.kind == OrderKind::Market
+    }
+
+    /// Smallest quantity order might be executed with
+    #[inline]
+    pub fn min_fill(&self) -> Q {
+        if self.time_in_force == TimeInForce::FillOrKill {
+            return self.quantity;
+        }
+        match self.condition {
+            FillCondition::Partial => Q::ONE,
+            FillCondition::AllOrNone => self.quantity,
+            FillCondition::MinQuantity(quantity) => quantity.min(self.quantity),
+        }
+    }
+
+    /// Order waits for trigger and is not in the book
+    #[inline]
+    pub fn is_stop(&self) -> bool {
+        matches!(
+            self.kind,
+            OrderKind::Stop { .. } | OrderKind::StopLimit { .. }
+        )
+    }
+
+    /// Traded `rate` reached trigger price of stop order: rose to it for Buy, fell to it for Sell
+    #[inline]
+    pub fn triggered_by(&self, rate: P) -> bool {
+        match (self.kind, self.order_type) {
+            (OrderKind::Stop { trigger }, OrderType::Buy)
+            | (OrderKind::StopLimit { trigger }, OrderType::Buy) => rate >= trigger,
+            (OrderKind::Stop { trigger }, OrderType::Sell)
+            | (OrderKind::StopLimit { trigger }, OrderType::Sell) => rate <= trigger,
+            _ => false,
+        }
+    }
+
+    /// Quantity not shown in book depth
+    #[inline]
+    pub fn hidden(&self) -> Q {
+        self.quantity - self.displayed
+    }
+
+    /// Execute `quantity`, displayed tranche is consumed first
+    #[inline]
+    pub fn fill(&mut self, quantity: Q) {
+        self.quantity -= quantity;
+        self.displayed = self.displayed - self.displayed.min(quantity);
+    }
+
+    /// Set lower remaining quantity, hidden quantity is reduced first
+    #[inline]
+    pub fn set_quantity(&mut self, quantity: Q) {
+        self.quantity = quantity;
+        self.displayed = self.displayed.min(quantity);
+    }
+
+    /// Displayed tranche of iceberg order is executed while hidden quantity is left
+    #[inline]
+    pub fn needs_refresh(&self) -> bool {
+        self.displayed == Q::ZERO && self.quantity > Q::ZERO
+    }
+
+    /// Order has to leave the book after auction of `epoch`
+    #[inline]
+    pub fn expires_after(&self, epoch: Epoch) -> bool {
+        match self.time_in_force {
+            TimeInForce::GoodTillCancel => false,
+            TimeInForce::GoodForAuction | TimeInForce::FillOrKill => true,
+            TimeInForce::GoodTillEpoch(_) => self.outlives(epoch),
+        }
+    }
+
+    /// Last epoch of the order is `epoch` or earlier, whether its auctions took place or not
     #[inline]
-    pub fn init_from_order(id: OrderId, epoch: Epoch, order: Order) -> Self {
+    pub fn outlives(&self, epoch: Epoch) -> bool {
+        matches!(self.time_in_force, TimeInForce::GoodTillEpoch(last) if last <= epoch)
+    }
+
+    #[inline]
+    pub fn init_from_order(id: OrderId, epoch: Epoch, sequence: u64, order: Order<P, Q>) -> Self {
         Self {
             id,
             epoch,
+            sequence,
+            instrument: order.instrument,
+            account: order.account,
+            kind: order.kind,
+            condition: order.condition,
+            time_in_force: order.time_in_force,
+            peak: order.peak,
             order_type: order.order_type,
             rate: order.rate,
             quantity: order.quantity,
+            displayed: tranche(order.peak, order.quantity),
         }
     }
 }
 
-impl RegisteredOrders {
+impl<P: PriceValue, Q: QuantityValue> RegisteredOrders<P, Q> {
+    /// Registry taking ids of new orders from key space shared with other registries
+    pub fn with_ids(ids: OrderIds) -> Self {
+        Self {
+            orders: SecondaryMap::new(),
+            ids,
+            free: Vec::new(),
+            slots: 0,
+            filled: SparseSecondaryMap::new(),
+            sequence: 0,
+            accounts: HashMap::new(),
+        }
+    }
+
     #[inline]
-    pub fn remove_order(&mut self, id: OrderId) -> Option<RegisteredOrder> {
-        self.orders.remove(id)
+    pub fn remove_order(&mut self, id: OrderId) -> Option<RegisteredOrder<P, Q>> {
+        let order = self.orders.remove(id)?;
+        self.release(id);
+        self.unindex(&order);
+        Some(order)
     }
 
     #[inline]
-    pub fn get(&mut self, id: OrderId) -> Option<&RegisteredOrder> {
+    pub fn get(&mut self, id: OrderId) -> Option<&RegisteredOrder<P, Q>> {
         self.orders.get(id)
     }
 
     #[inline]
-    pub fn add_order(&mut self, order: Order, epoch: Epoch) -> OrderId {
-        self.orders
-            .insert_with_key(|id| RegisteredOrder::init_from_order(id, epoch, order))
+    pub fn add_order(&mut self, order: Order<P, Q>, epoch: Epoch) -> OrderId {
+        self.sequence += 1;
+        let sequence = self.sequence;
+        let account = order.account;
+        let id = self.next_id();
+        self.orders.insert(
+            id,
+            RegisteredOrder::init_from_order(id, epoch, sequence, order),
+        );
+        self.accounts.entry(account).or_default().insert(id, ());
+        id
     }
 
     #[inline]
-    pub fn add_get_order(&mut self, order: Order, epoch: Epoch) -> RegisteredOrder {
+    pub fn add_get_order(&mut self, order: Order<P, Q>, epoch: Epoch) -> RegisteredOrder<P, Q> {
         let id = self.add_order(order, epoch);
         self[id].clone()
     }
 
+    /// Amend price and/or quantity of live order.
+    /// Reducing quantity keeps order's priority, any other change requeues it in `epoch`.
+    pub fn modify_order(
+        &mut self,
+        order: RegisteredOrder<P, Q>,
+        epoch: Epoch,
+    ) -> ModifyResult<P, Q> {
+        let original = match self.orders.get_mut(order.id) {
+            Some(original) => original,
+            None if self.filled.contains_key(order.id) => return ModifyResult::AlreadyFilled,
+            None => return ModifyResult::UnknownOrder,
+        };
+        if order.quantity == Q::ZERO
+            || order.instrument != original.instrument
+            || order.order_type != original.order_type
+            || order.account != original.account
+            || order.kind != original.kind
+            || order.condition != original.condition
+            || order.time_in_force != original.time_in_force
+            || order.peak != original.peak
+            || (order.kind.has_market_rate() && order.rate != original.rate)
+        {
+            return ModifyResult::Invalid;
+        }
+        if order.rate == original.rate && order.quantity <= original.quantity {
+            original.set_quantity(order.quantity);
+            ModifyResult::Reduced(original.clone())
+        } else {
+            self.sequence += 1;
+            original.rate = order.rate;
+            original.quantity = order.quantity;
+            original.displayed = tranche(original.peak, order.quantity);
+            original.epoch = epoch;
+            original.sequence = self.sequence;
+            ModifyResult::Requeued(original.clone())
+        }
+    }
+
+    /// Register fill of `quantity` against order.
+    /// Returns remaining order if it was only partially filled.
+    pub fn fill_order(&mut self, id: OrderId, quantity: Q) -> Option<RegisteredOrder<P, Q>> {
+        let order = self.orders.get_mut(id)?;
+        if quantity < order.quantity {
+            order.fill(quantity);
+            Some(order.clone())
+        } else {
+            self.remove_order(id);
+            self.filled.insert(id, ());
+            None
+        }
+    }
+
+    /// Convert triggered stop order to market or limit one, it is queued as of `epoch`
+    pub fn trigger_order(&mut self, id: OrderId, epoch: Epoch) -> Option<RegisteredOrder<P, Q>> {
+        let order = self.orders.get_mut(id)?;
+        order.kind = match order.kind {
+            OrderKind::Stop { .. } => OrderKind::Market,
+            OrderKind::StopLimit { .. } => OrderKind::Limit,
+            _ => return None,
+        };
+        self.sequence += 1;
+        order.epoch = epoch;
+        order.sequence = self.sequence;
+        Some(order.clone())
+    }
+
+    /// Display next tranche of iceberg order, it is queued again as of `epoch`
+    pub fn refresh_order(&mut self, id: OrderId, epoch: Epoch) -> Option<RegisteredOrder<P, Q>> {
+        let order = self.orders.get_mut(id)?;
+        self.sequence += 1;
+        order.displayed = tranche(order.peak, order.quantity);
+        order.epoch = epoch;
+        order.sequence = self.sequence;
+        Some(order.clone())
+    }
+
+    /// Live orders of the account
+    pub fn orders_of(&self, account: AccountId) -> impl Iterator<Item = OrderId> + '_ {
+        self.accounts
+            .get(&account)
+            .into_iter()
+            .flat_map(|orders| orders.keys())
+    }
+
+    /// Remove all live orders of the account
+    pub fn cancel_all(&mut self, account: AccountId) -> Vec<RegisteredOrder<P, Q>> {
+        let ids = match self.accounts.remove(&account) {
+            Some(ids) => ids,
+            None => return Vec::new(),
+        };
+        let cancelled: Vec<_> = ids.keys().filter_map(|id| self.orders.remove(id)).collect();
+        for order in cancelled.iter() {
+            self.release(order.id);
+        }
+        cancelled
+    }
+
+    /// Keep only orders matching predicate
+    pub fn retain(&mut self, mut f: impl FnMut(OrderId, &mut RegisteredOrder<P, Q>) -> bool) {
+        let mut removed = Vec::new();
+        self.orders.retain(|id, order| {
+            let keep = f(id, order);
+            if !keep {
+                removed.push(order.clone());
+            }
+            keep
+        });
+        for order in removed.iter() {
+            self.release(order.id);
+            self.unindex(order);
+        }
+    }
+
+    /// Id in free slot with version not given out before
     #[inline]
-    pub fn modify_order(&mut self, order: RegisteredOrder) {
-        if let Some(original) = self.orders.get_mut(order.id) {
-            *original = order;
+    fn next_id(&mut self) -> OrderId {
+        let slot = match self.free.pop() {
+            Some(slot) => slot,
+            None => {
+                self.slots += 1;
+                self.slots - 1
+            }
+        };
+        let version = self.ids.next_version();
+        KeyData::from_ffi((version as u64) << 32 | slot as u64).into()
+    }
+
+    #[inline]
+    fn release(&mut self, id: OrderId) {
+        // Low half of key is its slot index
+        self.free.push(id.data().as_ffi() as u32);
+    }
+
+    #[inline]
+    fn unindex(&mut self, order: &RegisteredOrder<P, Q>) {
+        if let Some(orders) = self.accounts.get_mut(&order.account) {
+            orders.remove(order.id);
+            if orders.is_empty() {
+                self.accounts.remove(&order.account);
+            }
         }
     }
+
+    /// Forget orders filled in previous auctions
+    #[inline]
+    pub fn clear_filled(&mut self) {
+        self.filled.clear();
+    }
 }
 
-impl Deref for RegisteredOrders {
-    type Target = HopSlotMap<OrderId, RegisteredOrder>;
+impl<P, Q> Deref for RegisteredOrders<P, Q> {
+    type Target = SecondaryMap<OrderId, RegisteredOrder<P, Q>>;
     fn deref(&self) -> &Self::Target {
         &self.orders
     }
 }
-impl DerefMut for RegisteredOrders {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.orders
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_order(orders: &mut RegisteredOrders, rate: Price, quantity: u32) -> RegisteredOrder {
+        orders.add_get_order(Order::limit(1, OrderType::Buy, rate, quantity), 0)
+    }
+
+    #[test]
+    fn modify_order() {
+        let mut orders = RegisteredOrders::default();
+        let order = test_order(&mut orders, 100, 10);
+
+        let mut amend = order.clone();
+        amend.quantity = 5;
+        match orders.modify_order(amend, 1) {
+            ModifyResult::Reduced(order) => {
+                assert_eq!(order.quantity, 5);
+                assert_eq!(order.epoch, 0);
+            }
+            result => panic!("Unexpected {:?}", result),
+        }
+
+        let mut amend = order.clone();
+        amend.rate = 101;
+        amend.quantity = 5;
+        match orders.modify_order(amend, 1) {
+            ModifyResult::Requeued(requeued) => {
+                assert_eq!(requeued.rate, 101);
+                assert_eq!(requeued.epoch, 1);
+                assert!(requeued.sequence > order.sequence);
+            }
+            result => panic!("Unexpected {:?}", result),
+        }
+
+        let mut amend = order.clone();
+        amend.quantity = 0;
+        assert_eq!(orders.modify_order(amend, 1), ModifyResult::Invalid);
+        let mut amend = order.clone();
+        amend.order_type = OrderType::Sell;
+        assert_eq!(orders.modify_order(amend, 1), ModifyResult::Invalid);
+        let mut amend = order.clone();
+        amend.account = 2;
+        assert_eq!(orders.modify_order(amend, 1), ModifyResult::Invalid);
+
+        assert_eq!(orders.fill_order(order.id, 5), None);
+        assert_eq!(
+            orders.modify_order(order.clone(), 2),
+            ModifyResult::AlreadyFilled
+        );
+        orders.clear_filled();
+        assert_eq!(orders.modify_order(order, 2), ModifyResult::UnknownOrder);
+    }
+
+    #[test]
+    fn order_slots_are_reused() {
+        let ids = OrderIds::default();
+        let mut orders = RegisteredOrders::with_ids(ids.clone());
+        let mut other = RegisteredOrders::<Price, Quantity>::with_ids(ids);
+        let first = test_order(&mut orders, 100, 10);
+        orders.remove_order(first.id);
+        let second = test_order(&mut orders, 100, 10);
+        assert_eq!(
+            first.id.data().as_ffi() as u32,
+            second.id.data().as_ffi() as u32
+        );
+        assert_ne!(first.id, second.id);
+        assert!(!orders.contains_key(first.id));
+        let foreign = test_order(&mut other, 100, 10);
+        assert!(!orders.contains_key(foreign.id));
+        assert_eq!(orders.len(), 1);
+    }
+
+    #[test]
+    fn account_orders() {
+        let mut orders = RegisteredOrders::default();
+        let first = test_order(&mut orders, 100, 10);
+        let second = test_order(&mut orders, 101, 10);
+        let other = orders.add_get_order(Order::limit(2, OrderType::Sell, 102, 10), 0);
+        let mut own: Vec<_> = orders.orders_of(1).collect();
+        own.sort();
+        assert_eq!(own, vec![first.id, second.id]);
+
+        orders.fill_order(first.id, 10);
+        assert_eq!(orders.orders_of(1).collect::<Vec<_>>(), vec![second.id]);
+        assert_eq!(orders.cancel_all(1), vec![second]);
+        assert_eq!(orders.orders_of(1).count(), 0);
+        assert_eq!(orders.cancel_all(1), vec![]);
+
+        orders.retain(|_, order| order.rate < 100);
+        assert_eq!(orders.orders_of(2).count(), 0);
+        assert!(orders.get(other.id).is_none());
     }
 }
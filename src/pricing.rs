@@ -0,0 +1,409 @@
+//! Clearing price rules of the double auction.
+//! Rule is applied once maximum executable volume is known,
+//! any price within `[marginal_ask, marginal_bid]` executes that volume.
+
+use crate::{
+    fixed::{notional, PriceValue, QuantityValue},
+    market::{ClearingRule, ClearingStep, PriceLevel},
+    orders::{Price, Quantity, RegisteredOrder},
+};
+
+/// Auction state available to pricing rule
+pub struct PricingContext<'a, P = Price, Q = Quantity> {
+    /// Price levels with maximum executable volume, ascending
+    pub candidates: &'a [PriceLevel<P>],
+    /// Maximum executable volume
+    pub volume: u64,
+    /// Lowest limit price of executed bids
+    pub marginal_bid: P,
+    /// Highest limit price of executed asks
+    pub marginal_ask: P,
+    pub reference_price: Option<P>,
+    /// Interpolated prices are rounded to multiple of it
+    pub tick_size: P,
+    /// Executed bids in priority order, the last one might be filled partially
+    pub bids: &'a [RegisteredOrder<P, Q>],
+    /// Executed asks in priority order, the last one might be filled partially
+    pub asks: &'a [RegisteredOrder<P, Q>],
+}
+
+pub trait PricingRule<P = Price, Q = Quantity>: Send + Sync {
+    /// Choose clearing price within `[marginal_ask, marginal_bid]`,
+    /// rules applied are reported in `steps`
+    fn clearing_price(
+        &self,
+        context: &PricingContext<'_, P, Q>,
+        steps: &mut Vec<ClearingStep<P>>,
+    ) -> P;
+}
+
+/// Venue rulebook: minimum surplus, then market pressure, then reference price
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CallAuctionPricing;
+
+/// Price splits marginal bid and ask in proportion `k * bid + (1 - k) * ask`
+#[derive(Debug, Clone, Copy)]
+pub struct KDoublePricing {
+    k: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MidpointPricing;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LastBidPricing;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LastAskPricing;
+
+/// Reference price bounded by marginal bid and ask, midpoint without reference
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReferencePricing;
+
+/// Average of executed limit prices weighted by executed quantity
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VolumeWeightedPricing;
+
+impl<P: PriceValue, Q: QuantityValue> PricingContext<'_, P, Q> {
+    #[inline]
+    fn midpoint(&self) -> P {
+        self.on_tick(self.marginal_ask.midpoint(self.marginal_bid))
+    }
+
+    /// Nearest price on tick within `[marginal_ask, marginal_bid]`
+    #[inline]
+    fn on_tick(&self, rate: P) -> P {
+        round_to_tick(rate, self.tick_size, self.marginal_ask, self.marginal_bid)
+    }
+
+    #[inline]
+    fn bound(&self, rate: P) -> P {
+        rate.max(self.marginal_ask).min(self.marginal_bid)
+    }
+}
+
+impl<P: PriceValue, Q: QuantityValue> PricingRule<P, Q> for CallAuctionPricing {
+    fn clearing_price(
+        &self,
+        context: &PricingContext<'_, P, Q>,
+        steps: &mut Vec<ClearingStep<P>>,
+    ) -> P {
+        let candidates = context.candidates;
+        if let [level] = candidates {
+            return level.rate;
+        }
+
+        let min_surplus = candidates
+            .iter()
+            .map(|level| level.surplus().abs())
+            .min()
+            .unwrap_or(0);
+        let candidates: Vec<_> = candidates
+            .iter()
+            .filter(|level| level.surplus().abs() == min_surplus)
+            .copied()
+            .collect();
+        steps.push(ClearingStep::new(ClearingRule::MinimumSurplus, &candidates));
+        if let [level] = candidates[..] {
+            return level.rate;
+        }
+
+        let lowest = candidates[0].rate;
+        let highest = candidates[candidates.len() - 1].rate;
+        let (rule, rate) = if candidates.iter().all(|level| level.surplus() > 0) {
+            (ClearingRule::MarketPressure, highest)
+        } else if candidates.iter().all(|level| level.surplus() < 0) {
+            (ClearingRule::MarketPressure, lowest)
+        } else if let Some(reference) = context.reference_price {
+            // Any price within candidates range executes maximum volume
+            (
+                ClearingRule::ReferencePrice,
+                reference.max(lowest).min(highest),
+            )
+        } else {
+            let midpoint = lowest.midpoint(highest);
+            (
+                ClearingRule::Midpoint,
+                round_to_tick(midpoint, context.tick_size, lowest, highest),
+            )
+        };
+        steps.push(ClearingStep::single(rule, rate));
+        rate
+    }
+}
+
+impl KDoublePricing {
+    pub fn new(k: f64) -> Self {
+        assert!((0.0..=1.0).contains(&k), "k must be within [0, 1]");
+        Self { k }
+    }
+}
+
+impl<P: PriceValue, Q: QuantityValue> PricingRule<P, Q> for KDoublePricing {
+    fn clearing_price(
+        &self,
+        context: &PricingContext<'_, P, Q>,
+        steps: &mut Vec<ClearingStep<P>>,
+    ) -> P {
+        let (ask, bid) = (context.marginal_ask.to_raw(), context.marginal_bid.to_raw());
+        let spread = (bid as i128 - ask as i128) as f64;
+        let offset = (spread * self.k).round() as i128;
+        // Offset is within spread, so price stays within marginal bid and ask
+        let rate = P::from_raw((ask as i128 + offset) as i64).expect("price within spread");
+        let rate = context.on_tick(rate);
+        steps.push(ClearingStep::single(ClearingRule::PricingRule, rate));
+        rate
+    }
+}
+
+impl<P: PriceValue, Q: QuantityValue> PricingRule<P, Q> for MidpointPricing {
+    fn clearing_price(
+        &self,
+        context: &PricingContext<'_, P, Q>,
+        steps: &mut Vec<ClearingStep<P>>,
+    ) -> P {
+        let rate = context.midpoint();
+        steps.push(ClearingStep::single(ClearingRule::Midpoint, rate));
+        rate
+    }
+}
+
+impl<P: PriceValue, Q: QuantityValue> PricingRule<P, Q> for LastBidPricing {
+    fn clearing_price(
+        &self,
+        context: &PricingContext<'_, P, Q>,
+        steps: &mut Vec<ClearingStep<P>>,
+    ) -> P {
+        steps.push(ClearingStep::single(
+            ClearingRule::PricingRule,
+            context.marginal_bid,
+        ));
+        context.marginal_bid
+    }
+}
+
+impl<P: PriceValue, Q: QuantityValue> PricingRule<P, Q> for LastAskPricing {
+    fn clearing_price(
+        &self,
+        context: &PricingContext<'_, P, Q>,
+        steps: &mut Vec<ClearingStep<P>>,
+    ) -> P {
+        steps.push(ClearingStep::single(
+            ClearingRule::PricingRule,
+            context.marginal_ask,
+        ));
+        context.marginal_ask
+    }
+}
+
+impl<P: PriceValue, Q: QuantityValue> PricingRule<P, Q> for ReferencePricing {
+    fn clearing_price(
+        &self,
+        context: &PricingContext<'_, P, Q>,
+        steps: &mut Vec<ClearingStep<P>>,
+    ) -> P {
+        let (rule, rate) = match context.reference_price {
+            Some(reference) => (ClearingRule::ReferencePrice, context.bound(reference)),
+            None => (ClearingRule::Midpoint, context.midpoint()),
+        };
+        steps.push(ClearingStep::single(rule, rate));
+        rate
+    }
+}
+
+impl<P: PriceValue, Q: QuantityValue> PricingRule<P, Q> for VolumeWeightedPricing {
+    fn clearing_price(
+        &self,
+        context: &PricingContext<'_, P, Q>,
+        steps: &mut Vec<ClearingStep<P>>,
+    ) -> P {
+        let (bid_notional, bid_volume) = executed_notional(context.bids, context.volume);
+        let (ask_notional, ask_volume) = executed_notional(context.asks, context.volume);
+        let limit_volume = bid_volume + ask_volume;
+        if limit_volume == 0 {
+            // Only market orders executed, there is no limit price to weight
+            let rate = context.midpoint();
+            steps.push(ClearingStep::single(ClearingRule::PricingRule, rate));
+            return rate;
+        }
+        let average = (bid_notional + ask_notional) / limit_volume as i128;
+        let rate = context.on_tick(P::from_raw(average as i64).expect("average of limit prices"));
+        steps.push(ClearingStep::single(ClearingRule::PricingRule, rate));
+        rate
+    }
+}
+
+/// Nearest multiple of `tick_size` within `[lowest, highest]`, ties round down.
+/// Bounds are limit prices, so they are on tick themselves.
+fn round_to_tick<P: PriceValue>(rate: P, tick_size: P, lowest: P, highest: P) -> P {
+    let (raw, tick) = (rate.to_raw() as i128, tick_size.to_raw() as i128);
+    if tick <= 1 {
+        return rate.max(lowest).min(highest);
+    }
+    let down = raw - raw.rem_euclid(tick);
+    let nearest = if (raw - down) * 2 > tick {
+        down + tick
+    } else {
+        down
+    };
+    // Step back inside bounds, otherwise trade would not cross
+    let nearest = if nearest > highest.to_raw() as i128 {
+        nearest - tick
+    } else if nearest < lowest.to_raw() as i128 {
+        nearest + tick
+    } else {
+        nearest
+    };
+    P::from_raw(nearest as i64)
+        .expect("price within bounds")
+        .max(lowest)
+        .min(highest)
+}
+
+/// Notional and volume of executed limit orders, market orders carry no price
+fn executed_notional<P: PriceValue, Q: QuantityValue>(
+    orders: &[RegisteredOrder<P, Q>],
+    volume: u64,
+) -> (i128, u64) {
+    let mut remaining = volume;
+    // Volume fits i64 and price fits i64, so notional of both sides fits i128
+    let mut total: i128 = 0;
+    let mut limit_volume = 0;
+    for order in orders {
+        let quantity = remaining.min(order.quantity.to_raw());
+        remaining -= quantity;
+        if order.is_market() {
+            continue;
+        }
+        total += notional(order.rate, quantity);
+        limit_volume += quantity;
+    }
+    (total, limit_volume)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        market::{market_match, MatchConfig},
+        orders::{Order, OrderType, RegisteredOrders},
+        sorted_vec_orders::SortedOrders,
+        spec::InstrumentSpec,
+    };
+
+    fn books() -> (SortedOrders, SortedOrders) {
+        let mut registered = RegisteredOrders::default();
+        let mut bids = SortedOrders::new(OrderType::Buy);
+        let mut asks = SortedOrders::new(OrderType::Sell);
+        let mut add = |order_type, rate, quantity| {
+            let order = Order::limit(0, order_type, rate, quantity);
+            registered.add_get_order(order, 0)
+        };
+        let mut buy_samples = vec![add(OrderType::Buy, 110, 10), add(OrderType::Buy, 100, 10)];
+        let mut sell_samples = vec![add(OrderType::Sell, 80, 10), add(OrderType::Sell, 90, 10)];
+        bids.add_batch(&mut buy_samples);
+        asks.add_batch(&mut sell_samples);
+        (bids, asks)
+    }
+
+    #[test]
+    fn pricing_rules() {
+        let rules: Vec<(Box<dyn PricingRule>, Price)> = vec![
+            (Box::new(CallAuctionPricing), 97),
+            (Box::new(KDoublePricing::new(0.0)), 90),
+            (Box::new(KDoublePricing::new(0.25)), 93),
+            (Box::new(KDoublePricing::new(1.0)), 100),
+            (Box::new(MidpointPricing), 95),
+            (Box::new(LastBidPricing), 100),
+            (Box::new(LastAskPricing), 90),
+            (Box::new(ReferencePricing), 97),
+            (Box::new(VolumeWeightedPricing), 95),
+        ];
+        for (pricing, expected) in rules {
+            let (bids, asks) = books();
+            let config = MatchConfig {
+                pricing,
+                ..Default::default()
+            };
+            let result = market_match(bids, asks, Some(97), &config).unwrap();
+            assert_eq!(result.traded_volume, 20);
+            assert_eq!(result.traded_rate, Some(expected));
+        }
+    }
+
+    #[test]
+    fn interpolated_prices_on_tick() {
+        let rules: Vec<(Box<dyn PricingRule>, Price)> = vec![
+            (Box::new(CallAuctionPricing), 100),
+            (Box::new(KDoublePricing::new(0.25)), 95),
+            (Box::new(KDoublePricing::new(0.9)), 115),
+            (Box::new(MidpointPricing), 100),
+            (Box::new(ReferencePricing), 100),
+            (Box::new(VolumeWeightedPricing), 100),
+        ];
+        for (pricing, expected) in rules {
+            let mut registered = RegisteredOrders::default();
+            let mut bids = SortedOrders::new(OrderType::Buy);
+            let mut asks = SortedOrders::new(OrderType::Sell);
+            let mut buy_samples =
+                vec![registered.add_get_order(Order::limit(0, OrderType::Buy, 115, 10), 0)];
+            let mut sell_samples =
+                vec![registered.add_get_order(Order::limit(0, OrderType::Sell, 90, 10), 0)];
+            bids.add_batch(&mut buy_samples);
+            asks.add_batch(&mut sell_samples);
+            let config = MatchConfig {
+                spec: InstrumentSpec {
+                    tick_size: 5,
+                    ..Default::default()
+                },
+                pricing,
+                ..Default::default()
+            };
+            // Midpoint 102, k = 0.25 gives 96, k = 0.9 gives 113
+            let result = market_match(bids, asks, None, &config).unwrap();
+            assert_eq!(result.traded_rate, Some(expected));
+        }
+    }
+
+    #[test]
+    fn volume_weighted_partial_fill() {
+        let mut registered = RegisteredOrders::default();
+        let mut bids = SortedOrders::new(OrderType::Buy);
+        let mut asks = SortedOrders::new(OrderType::Sell);
+        let mut buy_samples =
+            vec![registered.add_get_order(Order::limit(0, OrderType::Buy, 200, 10), 0)];
+        let mut sell_samples =
+            vec![registered.add_get_order(Order::limit(0, OrderType::Sell, 90, 5), 0)];
+        bids.add_batch(&mut buy_samples);
+        asks.add_batch(&mut sell_samples);
+        let config = MatchConfig {
+            pricing: Box::new(VolumeWeightedPricing),
+            ..Default::default()
+        };
+        let result = market_match(bids, asks, None, &config).unwrap();
+        assert_eq!(result.traded_rate, Some(145));
+    }
+
+    #[test]
+    fn volume_weighted_skips_market_orders() {
+        let mut registered = RegisteredOrders::default();
+        let mut bids = SortedOrders::new(OrderType::Buy);
+        let mut asks = SortedOrders::new(OrderType::Sell);
+        let mut buy_samples = vec![
+            registered.add_get_order(Order::market(0, OrderType::Buy, 10), 0),
+            registered.add_get_order(Order::limit(0, OrderType::Buy, 100, 10), 0),
+        ];
+        let mut sell_samples =
+            vec![registered.add_get_order(Order::limit(0, OrderType::Sell, 90, 20), 0)];
+        bids.add_batch(&mut buy_samples);
+        asks.add_batch(&mut sell_samples);
+        let config = MatchConfig {
+            pricing: Box::new(VolumeWeightedPricing),
+            ..Default::default()
+        };
+        let result = market_match(bids, asks, None, &config).unwrap();
+        assert_eq!(result.traded_volume, 20);
+        // (100 * 10 + 90 * 20) / 30
+        assert_eq!(result.traded_rate, Some(93));
+    }
+}
@@ -1,112 +1,385 @@
-use crate::orders::{OrderId, OrderType, RegisteredOrder, RegisteredOrders};
+//! Book side as vector of orders sorted in priority order:
+//! -- New orders are appended and sorted in parallel, merged in or inserted
+//!    one by one, `apply_batch` picks the way by batch and book size
+//! -- Cancel finds order by its priority key with binary search and marks
+//!    it as tombstone in place
+//! -- Tombstones are compacted away once they make `1 / COMPACT_RATIO` of storage
+//!    or with the next added orders, which move orders anyway
+//!
+//! Tombstones stay inside, orders are only seen through `OrderBookSide` which skips them.
+
+use crate::{
+    book::OrderBookSide,
+    fixed::{PriceValue, QuantityValue},
+    orders::{Epoch, OrderId, OrderType, Price, Quantity, RegisteredOrder},
+    spec::{InstrumentSpec, SpecError},
+};
 use merging_iterator::MergeIter;
 use rayon::slice::ParallelSliceMut;
 use slotmap::SparseSecondaryMap;
-use std::{
-    collections::HashSet,
-    ops::{Deref, DerefMut},
-};
+use std::{cmp::Ordering, iter::FilterMap, slice};
+
+/// Storage is compacted when tombstones make this fraction of it
+pub const COMPACT_RATIO: usize = 4;
+
+/// Batches of at most this many new orders are inserted one by one
+pub const TARGETED_ADDS: usize = 8;
+
+/// How `apply_batch` puts new orders in and cancelled orders out of the book
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchStrategy {
+    /// Append new orders and sort storage in parallel, leave tombstones of cancels
+    Sort,
+    /// Sort new orders and merge them with storage, dropping cancelled orders
+    Merge,
+    /// Insert new orders one by one, leave tombstones of cancels
+    Targeted,
+}
+
+impl BatchStrategy {
+    /// Cheapest strategy for `adds` new orders to book of `len` orders:
+    /// each inserted order shifts storage, merge copies it once
+    /// and parallel sort pays off once batch outgrows the book
+    pub fn pick(len: usize, adds: usize) -> Self {
+        if adds <= TARGETED_ADDS {
+            BatchStrategy::Targeted
+        } else if adds >= len {
+            BatchStrategy::Sort
+        } else {
+            BatchStrategy::Merge
+        }
+    }
+}
 
-pub struct SortedOrders {
+#[derive(Debug)]
+pub struct SortedOrders<P = Price, Q = Quantity> {
     order_type: OrderType,
-    orders: Vec<RegisteredOrder>,
+    orders: Vec<Entry<P, Q>>,
+    /// Priority key of orders added by batch methods, to find them on cancel
+    keys: SparseSecondaryMap<OrderId, PriorityKey<P>>,
+    /// Cancelled orders left in storage
+    tombstones: usize,
+}
+
+/// Order in storage, cancelled one keeps its place until compaction
+#[derive(Debug, Clone)]
+pub struct Entry<P, Q> {
+    order: RegisteredOrder<P, Q>,
+    cancelled: bool,
 }
 
-impl SortedOrders {
+impl<P, Q> Entry<P, Q> {
+    #[inline]
+    fn new(order: RegisteredOrder<P, Q>) -> Self {
+        Self {
+            order,
+            cancelled: false,
+        }
+    }
+
+    #[inline]
+    fn live(&self) -> Option<&RegisteredOrder<P, Q>> {
+        (!self.cancelled).then_some(&self.order)
+    }
+}
+
+/// What `order_priority` compares orders by
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PriorityKey<P> {
+    market: bool,
+    rate: P,
+    epoch: Epoch,
+    sequence: u64,
+}
+
+impl<P: PriceValue> PriorityKey<P> {
+    #[inline]
+    fn of<Q: QuantityValue>(order: &RegisteredOrder<P, Q>) -> Self {
+        Self {
+            market: order.is_market(),
+            rate: order.rate,
+            epoch: order.epoch,
+            sequence: order.sequence,
+        }
+    }
+
+    #[inline]
+    fn cmp(&self, order_type: OrderType, other: &Self) -> Ordering {
+        let by_rate = match order_type {
+            OrderType::Buy => other.rate.cmp(&self.rate),
+            OrderType::Sell => self.rate.cmp(&other.rate),
+        };
+        other
+            .market
+            .cmp(&self.market)
+            .then(by_rate)
+            .then(self.epoch.cmp(&other.epoch))
+            .then(self.sequence.cmp(&other.sequence))
+    }
+}
+
+impl<P: PriceValue, Q: QuantityValue> SortedOrders<P, Q> {
     pub fn new(order_type: OrderType) -> Self {
         Self {
             order_type,
             orders: Default::default(),
+            keys: SparseSecondaryMap::new(),
+            tombstones: 0,
         }
     }
 
-    pub fn add_batch(&mut self, new_orders: &mut Vec<RegisteredOrder>) {
-        //let time = Instant::now();
-        self.orders.extend_from_slice(&std::mem::take(new_orders));
-        if self.order_type == OrderType::Buy {
-            self.orders.par_sort_by(|a, b| b.rate.cmp(&a.rate));
-        } else {
-            self.orders.par_sort_by(|a, b| a.rate.cmp(&b.rate));
+    /// Cancelled orders still in storage
+    #[inline]
+    pub fn tombstones(&self) -> usize {
+        self.tombstones
+    }
+
+    /// Drop tombstones from storage
+    pub fn compact(&mut self) {
+        if self.tombstones > 0 {
+            self.orders.retain(|entry| !entry.cancelled);
+            self.tombstones = 0;
         }
+    }
+
+    /// Position of order `id` in storage if it is live there
+    fn position(&self, id: OrderId) -> Option<usize> {
+        let key = self.keys.get(id)?;
+        let order_type = self.order_type;
+        let idx = self
+            .orders
+            .binary_search_by(|entry| PriorityKey::of(&entry.order).cmp(order_type, key))
+            .ok()?;
+        let order = self.orders[idx].live()?;
+        (order.id == id).then_some(idx)
+    }
+
+    fn index<'a>(&mut self, orders: impl Iterator<Item = &'a RegisteredOrder<P, Q>>)
+    where
+        P: 'a,
+        Q: 'a,
+    {
+        for order in orders {
+            self.keys.insert(order.id, PriorityKey::of(order));
+        }
+    }
+
+    #[inline]
+    pub fn order_type(&self) -> OrderType {
+        self.order_type
+    }
+
+    pub fn add_batch(&mut self, new_orders: &mut Vec<RegisteredOrder<P, Q>>) {
+        if new_orders.is_empty() {
+            return;
+        }
+        //let time = Instant::now();
+        self.index(new_orders.iter());
+        self.compact();
+        self.orders
+            .extend(std::mem::take(new_orders).into_iter().map(Entry::new));
+        let order_type = self.order_type;
+        self.orders
+            .par_sort_by(|a, b| order_priority(order_type, &a.order, &b.order));
         //println!("Merged {:?} orders {} in {} micros", self.order_type, self.orders.len(), time.elapsed().as_micros());
     }
 
-    pub fn add_remove_batch(
+    /// Add new orders, then cancel orders of `cancels` among them and in the book,
+    /// with strategy picked by `BatchStrategy::pick`
+    pub fn apply_batch(
         &mut self,
-        new_orders: &mut Vec<RegisteredOrder>,
-        orders: &RegisteredOrders,
+        adds: &mut Vec<RegisteredOrder<P, Q>>,
+        cancels: &SparseSecondaryMap<OrderId, ()>,
     ) {
-        let mut new_orders = std::mem::take(new_orders);
-        if self.order_type == OrderType::Buy {
-            new_orders.sort_unstable_by(|a, b| b.rate.cmp(&a.rate));
-        } else {
-            new_orders.sort_unstable_by(|a, b| a.rate.cmp(&b.rate));
+        let strategy = BatchStrategy::pick(self.len(), adds.len());
+        self.apply_batch_with(strategy, adds, cancels);
+    }
+
+    /// `apply_batch` with given strategy, all of them leave the same orders in the book
+    pub fn apply_batch_with(
+        &mut self,
+        strategy: BatchStrategy,
+        adds: &mut Vec<RegisteredOrder<P, Q>>,
+        cancels: &SparseSecondaryMap<OrderId, ()>,
+    ) {
+        match strategy {
+            BatchStrategy::Sort => {
+                self.add_batch(adds);
+                self.cancel(cancels);
+            }
+            BatchStrategy::Merge => self.merge_batch(adds, cancels),
+            BatchStrategy::Targeted => {
+                adds.retain(|order| !cancels.contains_key(order.id));
+                self.cancel(cancels);
+                self.insert_each(adds);
+            }
         }
-        //let time = Instant::now();
-        let new_orders = new_orders
-            .into_iter()
-            .filter(|order| orders.contains_key(order.id));
-        let self_orders = std::mem::take(&mut self.orders)
-            .into_iter()
-            .filter(|order| orders.contains_key(order.id));
-        self.orders = if self.order_type == OrderType::Buy {
-            MergeIter::with_custom_ordering(new_orders, self_orders, |a, b| b.rate < a.rate)
-                .collect()
-        } else {
-            MergeIter::with_custom_ordering(new_orders, self_orders, |a, b| a.rate < b.rate)
-                .collect()
-        };
-        //println!("Merged {:?} orders {} in {} micros", self.order_type, self.orders.len(), time.elapsed().as_micros());
     }
 
-    pub fn add_remove_hash_set_batch(
+    /// Sort new orders and merge them with storage, dropping tombstones
+    /// and cancelled orders on the way
+    fn merge_batch(
         &mut self,
-        add: &mut Vec<RegisteredOrder>,
-        remove: &mut HashSet<OrderId>,
+        adds: &mut Vec<RegisteredOrder<P, Q>>,
+        cancels: &SparseSecondaryMap<OrderId, ()>,
     ) {
-        let remove_set = std::mem::take(remove);
-        let mut orders = std::mem::take(add);
-        if self.order_type == OrderType::Buy {
-            orders.sort_unstable_by(|a, b| b.rate.cmp(&a.rate));
-        } else {
-            orders.sort_unstable_by(|a, b| a.rate.cmp(&b.rate));
+        let mut adds = std::mem::take(adds);
+        let order_type = self.order_type;
+        adds.sort_unstable_by(|a, b| order_priority(order_type, a, b));
+        self.index(adds.iter());
+        for id in cancels.keys() {
+            self.keys.remove(id);
         }
         //let time = Instant::now();
-        let orders = orders
+        let adds = adds
             .into_iter()
-            .filter(|order| !remove_set.contains(&order.id));
+            .filter(|order| !cancels.contains_key(order.id))
+            .map(Entry::new);
         let self_orders = std::mem::take(&mut self.orders)
             .into_iter()
-            .filter(|order| !remove_set.contains(&order.id));
-        self.orders = if self.order_type == OrderType::Buy {
-            MergeIter::with_custom_ordering(orders, self_orders, |a, b| b.rate < a.rate).collect()
-        } else {
-            MergeIter::with_custom_ordering(orders, self_orders, |a, b| a.rate < b.rate).collect()
+            .filter(|entry| !entry.cancelled && !cancels.contains_key(entry.order.id));
+        self.orders = match order_type {
+            OrderType::Buy => MergeIter::with_custom_ordering(adds, self_orders, |a, b| {
+                order_priority(OrderType::Buy, &a.order, &b.order) == Ordering::Less
+            })
+            .collect(),
+            OrderType::Sell => MergeIter::with_custom_ordering(adds, self_orders, |a, b| {
+                order_priority(OrderType::Sell, &a.order, &b.order) == Ordering::Less
+            })
+            .collect(),
         };
+        self.tombstones = 0;
         //println!("Merged {:?} orders {} in {} micros", self.order_type, self.orders.len(), time.elapsed().as_micros());
     }
 
-    pub fn remove_batch(&mut self, orders: &SparseSecondaryMap<OrderId, ()>) {
-        self.orders.retain(|order| !orders.contains_key(order.id));
+    /// Insert new orders one by one at their place in storage
+    fn insert_each(&mut self, adds: &mut Vec<RegisteredOrder<P, Q>>) {
+        let order_type = self.order_type;
+        self.index(adds.iter());
+        for order in adds.drain(..) {
+            let key = PriorityKey::of(&order);
+            let idx = self
+                .orders
+                .binary_search_by(|queued| PriorityKey::of(&queued.order).cmp(order_type, &key))
+                .unwrap_or_else(|idx| idx);
+            self.orders.insert(idx, Entry::new(order));
+        }
     }
-}
 
-impl Deref for SortedOrders {
-    type Target = Vec<RegisteredOrder>;
-    fn deref(&self) -> &Self::Target {
-        &self.orders
+    /// Leave tombstones of cancelled orders, ids not in the book are ignored
+    fn cancel(&mut self, ids: &SparseSecondaryMap<OrderId, ()>) {
+        for id in ids.keys() {
+            if let Some(idx) = self.position(id) {
+                self.orders[idx].cancelled = true;
+                self.tombstones += 1;
+            }
+            self.keys.remove(id);
+        }
+        if self.tombstones * COMPACT_RATIO >= self.orders.len() {
+            self.compact();
+        }
     }
 }
-impl DerefMut for SortedOrders {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.orders
+
+impl<P: PriceValue, Q: QuantityValue> OrderBookSide<P, Q> for SortedOrders<P, Q> {
+    type Iter<'a> =
+        FilterMap<slice::Iter<'a, Entry<P, Q>>, fn(&Entry<P, Q>) -> Option<&RegisteredOrder<P, Q>>>;
+
+    #[inline]
+    fn new(order_type: OrderType, _spec: &InstrumentSpec<P, Q>) -> Result<Self, SpecError<P, Q>> {
+        Ok(SortedOrders::new(order_type))
     }
+
+    #[inline]
+    fn order_type(&self) -> OrderType {
+        self.order_type
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        self.orders.len() - self.tombstones
+    }
+
+    #[inline]
+    fn iter(&self) -> Self::Iter<'_> {
+        self.orders.iter().filter_map(Entry::live)
+    }
+
+    #[inline]
+    fn insert_batch(&mut self, orders: &mut Vec<RegisteredOrder<P, Q>>) {
+        self.add_batch(orders);
+    }
+
+    #[inline]
+    fn remove_batch(&mut self, ids: &SparseSecondaryMap<OrderId, ()>) {
+        self.cancel(ids);
+    }
+
+    #[inline]
+    fn apply_batch(
+        &mut self,
+        adds: &mut Vec<RegisteredOrder<P, Q>>,
+        cancels: &SparseSecondaryMap<OrderId, ()>,
+    ) {
+        SortedOrders::apply_batch(self, adds, cancels);
+    }
+
+    fn amend_batch(&mut self, amends: &SparseSecondaryMap<OrderId, Q>) {
+        for (id, quantity) in amends.iter() {
+            if let Some(idx) = self.position(id) {
+                self.orders[idx].order.set_quantity(*quantity);
+            }
+        }
+    }
+
+    fn drain_prefix(&mut self, count: usize) -> Vec<RegisteredOrder<P, Q>> {
+        let mut live = 0;
+        let end = self
+            .orders
+            .iter()
+            .position(|entry| {
+                live += !entry.cancelled as usize;
+                live > count
+            })
+            .unwrap_or(self.orders.len());
+        let drained: Vec<_> = self
+            .orders
+            .drain(..end)
+            .filter(|entry| !entry.cancelled)
+            .map(|entry| entry.order)
+            .collect();
+        self.tombstones -= end - drained.len();
+        for order in drained.iter() {
+            self.keys.remove(order.id);
+        }
+        drained
+    }
+
+    #[inline]
+    fn prepend_batch(&mut self, orders: Vec<RegisteredOrder<P, Q>>) {
+        self.index(orders.iter());
+        self.orders.splice(0..0, orders.into_iter().map(Entry::new));
+    }
+}
+
+/// Price-time priority: market orders first, then better price,
+/// then earlier epoch, then earlier arrival
+#[inline]
+pub fn order_priority<P: PriceValue, Q: QuantityValue>(
+    order_type: OrderType,
+    a: &RegisteredOrder<P, Q>,
+    b: &RegisteredOrder<P, Q>,
+) -> Ordering {
+    PriorityKey::of(a).cmp(order_type, &PriorityKey::of(b))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::orders::Order;
+    use crate::{
+        book::{level_volumes, DepthLevel},
+        orders::{Order, RegisteredOrders},
+    };
     use nanorand::WyRand;
 
     #[test]
@@ -121,7 +394,7 @@ mod tests {
                 .map(|(_, order)| registered.add_get_order(order, 0))
                 .take(10_000)
                 .collect();
-            orders.add_remove_batch(&mut samples, &registered);
+            orders.apply_batch(&mut samples, &SparseSecondaryMap::new());
             assert_eq!(
                 orders.iter().zip(orders.iter().skip(1)).find(|(a, b)| {
                     if *order_type == OrderType::Buy {
@@ -139,12 +412,276 @@ mod tests {
                 .map(|(_, order)| registered.add_get_order(order, 1))
                 .take(10_000)
                 .collect();
+            let cancels: SparseSecondaryMap<OrderId, ()> = registered
+                .values()
+                .filter(|order| order.rate >= 900)
+                .map(|order| (order.id, ()))
+                .collect();
             registered.retain(|_, order| order.rate < 900);
-            orders.add_remove_batch(&mut samples, &registered);
+            orders.apply_batch(&mut samples, &cancels);
             for order in orders.iter() {
                 assert!(registered.contains_key(order.id));
             }
             assert_eq!(orders.len(), registered.len());
         }
     }
+
+    #[test]
+    fn depth_shows_displayed_quantity() {
+        let mut registered = RegisteredOrders::default();
+        let mut book = SortedOrders::new(OrderType::Sell);
+        let mut samples = vec![
+            Order::limit(0, OrderType::Sell, 100, 10),
+            Order::limit(0, OrderType::Sell, 100, 50).with_peak(5),
+            Order::limit(0, OrderType::Sell, 101, 10),
+            Order::limit(0, OrderType::Sell, 102, 10),
+            Order::market(0, OrderType::Sell, 10),
+        ]
+        .into_iter()
+        .map(|order| registered.add_get_order(order, 0))
+        .collect();
+        book.add_batch(&mut samples);
+        assert_eq!(
+            book.depth(2),
+            vec![
+                DepthLevel {
+                    rate: 100,
+                    quantity: 15,
+                    orders: 2
+                },
+                DepthLevel {
+                    rate: 101,
+                    quantity: 10,
+                    orders: 1
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn sorted_orders_price_time_priority() {
+        for order_type in &[OrderType::Buy, OrderType::Sell] {
+            let mut registered = RegisteredOrders::default();
+            let mut rng = WyRand::new_seed(2);
+            let mut batch = |epoch| -> Vec<_> {
+                (0..)
+                    .map(|_| Order::random(&mut rng, 100, 110, 0))
+                    .filter(|order| order.order_type == *order_type)
+                    .map(|order| registered.add_get_order(order, epoch))
+                    .take(1_000)
+                    .collect()
+            };
+            let batches = [batch(0), batch(1), batch(1)];
+            let mut by_add = SortedOrders::new(*order_type);
+            let mut by_merge = SortedOrders::new(*order_type);
+            let mut by_insert = SortedOrders::new(*order_type);
+            let no_cancels = SparseSecondaryMap::new();
+            // Every strategy puts orders of earlier epoch ahead of the book
+            for samples in batches.iter().rev() {
+                by_add.add_batch(&mut samples.clone());
+                by_merge.apply_batch_with(BatchStrategy::Merge, &mut samples.clone(), &no_cancels);
+                by_insert.apply_batch_with(
+                    BatchStrategy::Targeted,
+                    &mut samples.clone(),
+                    &no_cancels,
+                );
+            }
+            let expected: Vec<_> = by_add.iter().collect();
+            assert!(expected
+                .windows(2)
+                .all(|pair| order_priority(*order_type, pair[0], pair[1]) == Ordering::Less));
+            assert!(by_merge.iter().eq(expected.iter().copied()));
+            assert!(by_insert.iter().eq(expected.iter().copied()));
+        }
+    }
+
+    #[test]
+    fn batch_strategies_agree() {
+        let strategies = [
+            BatchStrategy::Sort,
+            BatchStrategy::Merge,
+            BatchStrategy::Targeted,
+        ];
+        for order_type in &[OrderType::Buy, OrderType::Sell] {
+            let mut registered = RegisteredOrders::default();
+            let mut rng = WyRand::new_seed(8);
+            let mut batch = |size, epoch| -> Vec<_> {
+                (0..)
+                    .map(|i| match i % 50 {
+                        0 => Order::market(0, *order_type, 10),
+                        _ => Order::random(&mut rng, 100, 120, 0),
+                    })
+                    .map(|order| registered.add_get_order(order, epoch))
+                    .filter(|order| order.order_type == *order_type)
+                    .take(size)
+                    .collect()
+            };
+            // Filling empty book, batch of book size, small batch, single order,
+            // cancels only and earlier epoch behind the book
+            let batches = [
+                batch(2_000, 1),
+                batch(2_000, 1),
+                batch(100, 2),
+                batch(1, 2),
+                Vec::new(),
+                batch(100, 0),
+            ];
+            let mut books: Vec<_> = strategies
+                .iter()
+                .map(|_| SortedOrders::new(*order_type))
+                .chain(std::iter::once(SortedOrders::new(*order_type)))
+                .collect();
+            for (round, adds) in batches.iter().enumerate() {
+                // Cancels of orders in the book, among new ones and unknown to the book
+                let mut cancels: SparseSecondaryMap<OrderId, ()> = books[0]
+                    .iter()
+                    .skip(round)
+                    .step_by(7)
+                    .chain(adds.iter().step_by(3))
+                    .map(|order| (order.id, ()))
+                    .collect();
+                cancels.extend(
+                    registered
+                        .values()
+                        .filter(|order| order.order_type != *order_type)
+                        .take(10)
+                        .map(|order| (order.id, ())),
+                );
+                for (book, strategy) in books.iter_mut().zip(strategies.iter()) {
+                    book.apply_batch_with(*strategy, &mut adds.clone(), &cancels);
+                }
+                books[3].apply_batch(&mut adds.clone(), &cancels);
+                let expected: Vec<_> = books[0].iter().cloned().collect();
+                assert!(expected.windows(2).all(|pair| order_priority(
+                    *order_type,
+                    &pair[0],
+                    &pair[1]
+                ) == Ordering::Less));
+                assert!(expected.iter().all(|order| !cancels.contains_key(order.id)));
+                for book in books.iter() {
+                    assert_eq!(book.len(), expected.len());
+                    assert!(book.iter().eq(expected.iter()));
+                }
+            }
+            assert!(!books[0].is_empty());
+        }
+    }
+
+    #[test]
+    fn batch_strategy_by_size() {
+        assert_eq!(BatchStrategy::pick(0, 0), BatchStrategy::Targeted);
+        assert_eq!(
+            BatchStrategy::pick(100_000, TARGETED_ADDS),
+            BatchStrategy::Targeted
+        );
+        assert_eq!(BatchStrategy::pick(100_000, 10_000), BatchStrategy::Merge);
+        assert_eq!(BatchStrategy::pick(10_000, 10_000), BatchStrategy::Sort);
+        assert_eq!(BatchStrategy::pick(0, 10_000), BatchStrategy::Sort);
+    }
+
+    #[test]
+    fn zero_quantity_orders_are_not_tombstones() {
+        let mut registered = RegisteredOrders::default();
+        let mut orders: Vec<_> = [(100, 0), (101, 5), (102, 0), (103, 5), (104, 5), (105, 5)]
+            .iter()
+            .map(|&(rate, quantity)| {
+                registered.add_get_order(Order::limit(0, OrderType::Sell, rate, quantity), 0)
+            })
+            .collect();
+        let ids: Vec<_> = orders.iter().map(|order| order.id).collect();
+        let mut book = SortedOrders::new(OrderType::Sell);
+        book.add_batch(&mut orders);
+        book.remove_batch(&std::iter::once((ids[1], ())).collect());
+        assert_eq!(book.tombstones(), 1);
+        assert_eq!(book.len(), 5);
+
+        let drained = book.drain_prefix(2);
+        assert_eq!(
+            drained.iter().map(|order| order.id).collect::<Vec<_>>(),
+            [ids[0], ids[2]]
+        );
+        assert_eq!(book.tombstones(), 0);
+        assert_eq!(book.len(), 3);
+    }
+
+    #[test]
+    fn cancel_leaves_tombstones_until_compaction() {
+        for order_type in &[OrderType::Buy, OrderType::Sell] {
+            let mut registered = RegisteredOrders::default();
+            let mut rng = WyRand::new_seed(7);
+            let mut samples: Vec<_> = (0..)
+                .map(|i| match i % 100 {
+                    0 => Order::market(0, *order_type, 10),
+                    _ => Order::random(&mut rng, 100, 120, 0),
+                })
+                .map(|order| registered.add_get_order(order, 0))
+                .filter(|order| order.order_type == *order_type)
+                .take(1_000)
+                .collect();
+            let mut book = SortedOrders::new(*order_type);
+            book.add_batch(&mut samples);
+            let mut expected: Vec<_> = book.iter().cloned().collect();
+            let cancel = |book: &mut SortedOrders, expected: &mut Vec<RegisteredOrder>, step| {
+                let ids: SparseSecondaryMap<OrderId, ()> = expected
+                    .iter()
+                    .step_by(step)
+                    .map(|order| (order.id, ()))
+                    .collect();
+                book.remove_batch(&ids);
+                expected.retain(|order| !ids.contains_key(order.id));
+            };
+
+            // Best order and a few more are only marked
+            cancel(&mut book, &mut expected, 50);
+            assert_eq!(book.tombstones(), 20);
+            assert_eq!(book.len(), expected.len());
+            assert_eq!(book.iter().cloned().collect::<Vec<_>>(), expected);
+            assert_eq!(book.first(), expected.first());
+            // Cancel of unknown or already cancelled orders is ignored
+            let ids: SparseSecondaryMap<OrderId, ()> = registered
+                .keys()
+                .filter(|id| !expected.iter().any(|order| order.id == *id))
+                .map(|id| (id, ()))
+                .collect();
+            book.remove_batch(&ids);
+            assert_eq!(book.tombstones(), 20);
+
+            let amends: SparseSecondaryMap<OrderId, u32> = expected
+                .iter()
+                .step_by(7)
+                .map(|order| (order.id, 1))
+                .collect();
+            book.amend_batch(&amends);
+            for order in expected.iter_mut() {
+                if let Some(quantity) = amends.get(order.id) {
+                    order.set_quantity(*quantity);
+                }
+            }
+            assert_eq!(book.iter().cloned().collect::<Vec<_>>(), expected);
+
+            let best = book.drain_prefix(100);
+            assert_eq!(best, expected[..100]);
+            book.prepend_batch(best);
+            assert_eq!(book.iter().cloned().collect::<Vec<_>>(), expected);
+            assert_eq!(book.volumes_while(|_| true), level_volumes(expected.iter()));
+
+            // Storage is compacted once enough orders are cancelled
+            cancel(&mut book, &mut expected, 3);
+            assert_eq!(book.tombstones(), 0);
+            assert_eq!(book.iter().cloned().collect::<Vec<_>>(), expected);
+            cancel(&mut book, &mut expected, 50);
+            assert!(book.tombstones() > 0);
+            // Empty batch leaves storage alone, new orders compact it
+            book.add_batch(&mut Vec::new());
+            assert!(book.tombstones() > 0);
+            let mut late = vec![registered.add_get_order(Order::market(0, *order_type, 5), 1)];
+            expected.insert(
+                expected.iter().filter(|order| order.is_market()).count(),
+                late[0].clone(),
+            );
+            book.add_batch(&mut late);
+            assert_eq!(book.tombstones(), 0);
+            assert_eq!(book.iter().cloned().collect::<Vec<_>>(), expected);
+        }
+    }
 }
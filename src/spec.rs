@@ -0,0 +1,421 @@
+//! Static instrument parameters and validation of incoming orders against them:
+//! -- Limit and trigger prices on tick and within price band
+//! -- Quantity and iceberg peak in whole lots and within quantity limits
+//! -- Notional of limit orders within maximum
+//!
+//! Prices and quantities are fixed-point, spec gives their decimal scale.
+
+use std::{
+    convert::TryFrom,
+    fmt::{self, Display, Formatter},
+};
+
+use crate::{
+    fixed::{format_fixed, notional, parse_fixed, PriceValue, QuantityValue},
+    orders::{Order, OrderKind, Price, Quantity, RegisteredOrder},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InstrumentSpec<P = Price, Q = Quantity> {
+    /// Fractional decimal digits of prices
+    pub price_scale: u32,
+    /// Fractional decimal digits of quantities
+    pub quantity_scale: u32,
+    pub tick_size: P,
+    pub lot_size: Q,
+    pub min_quantity: Q,
+    pub max_quantity: Q,
+    pub min_price: P,
+    pub max_price: P,
+    /// Maximum of limit price times quantity, scaled by both price and quantity scale
+    pub max_notional: u128,
+}
+
+/// Reason order request does not conform to instrument spec
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejection<P = Price, Q = Quantity> {
+    OffTick { rate: P, tick_size: P },
+    OddLot { quantity: Q, lot_size: Q },
+    QuantityBelowMin { quantity: Q, min_quantity: Q },
+    QuantityAboveMax { quantity: Q, max_quantity: Q },
+    PriceBelowMin { rate: P, min_price: P },
+    PriceAboveMax { rate: P, max_price: P },
+    NotionalAboveMax { notional: u128, max_notional: u128 },
+}
+
+/// Reason instrument spec cannot be traded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpecError<P = Price, Q = Quantity> {
+    NonPositiveTickSize {
+        tick_size: P,
+    },
+    ZeroLotSize,
+    EmptyPriceRange {
+        min_price: P,
+        max_price: P,
+    },
+    EmptyQuantityRange {
+        min_quantity: Q,
+        max_quantity: Q,
+    },
+    /// Price range has more ticks than book storage holds
+    TooManyTicks {
+        ticks: u128,
+        max_ticks: usize,
+    },
+}
+
+impl<P: PriceValue, Q: QuantityValue> Default for InstrumentSpec<P, Q> {
+    /// Integer prices and quantities, any quantity,
+    /// any price apart from ones reserved for market orders
+    fn default() -> Self {
+        let tick = P::from_raw(1).expect("price type holds 1");
+        Self {
+            price_scale: 0,
+            quantity_scale: 0,
+            tick_size: tick,
+            lot_size: Q::ONE,
+            min_quantity: Q::ONE,
+            max_quantity: Q::MAX,
+            min_price: P::from_raw(P::MIN.to_raw() + 1).expect("next to minimum price"),
+            max_price: P::from_raw(P::MAX.to_raw() - 1).expect("next to maximum price"),
+            max_notional: u128::MAX,
+        }
+    }
+}
+
+impl<P: PriceValue, Q: QuantityValue> InstrumentSpec<P, Q> {
+    /// Check spec itself, orders are validated against valid specs only
+    pub fn validate(&self) -> Result<(), SpecError<P, Q>> {
+        if self.tick_size.to_raw() <= 0 {
+            return Err(SpecError::NonPositiveTickSize {
+                tick_size: self.tick_size,
+            });
+        }
+        if self.lot_size == Q::ZERO {
+            return Err(SpecError::ZeroLotSize);
+        }
+        if self.min_price > self.max_price {
+            return Err(SpecError::EmptyPriceRange {
+                min_price: self.min_price,
+                max_price: self.max_price,
+            });
+        }
+        if self.min_quantity > self.max_quantity {
+            return Err(SpecError::EmptyQuantityRange {
+                min_quantity: self.min_quantity,
+                max_quantity: self.max_quantity,
+            });
+        }
+        Ok(())
+    }
+
+    /// Check new order
+    pub fn validate_order(&self, order: &Order<P, Q>) -> Result<(), Rejection<P, Q>> {
+        self.validate_request(order.kind, order.rate, order.quantity, order.peak)
+    }
+
+    /// Check amended order
+    pub fn validate_amend(&self, order: &RegisteredOrder<P, Q>) -> Result<(), Rejection<P, Q>> {
+        self.validate_request(order.kind, order.rate, order.quantity, order.peak)
+    }
+
+    fn validate_request(
+        &self,
+        kind: OrderKind<P>,
+        rate: P,
+        quantity: Q,
+        peak: Option<Q>,
+    ) -> Result<(), Rejection<P, Q>> {
+        if quantity < self.min_quantity {
+            return Err(Rejection::QuantityBelowMin {
+                quantity,
+                min_quantity: self.min_quantity,
+            });
+        }
+        if quantity > self.max_quantity {
+            return Err(Rejection::QuantityAboveMax {
+                quantity,
+                max_quantity: self.max_quantity,
+            });
+        }
+        for quantity in std::iter::once(quantity).chain(peak) {
+            if quantity.to_raw() % self.lot_size.to_raw() != 0 {
+                return Err(Rejection::OddLot {
+                    quantity,
+                    lot_size: self.lot_size,
+                });
+            }
+        }
+        if let OrderKind::Stop { trigger } | OrderKind::StopLimit { trigger } = kind {
+            self.validate_price(trigger)?;
+        }
+        if kind.has_market_rate() {
+            return Ok(());
+        }
+        self.validate_price(rate)?;
+        let notional = notional(rate, quantity).unsigned_abs();
+        if notional > self.max_notional {
+            return Err(Rejection::NotionalAboveMax {
+                notional,
+                max_notional: self.max_notional,
+            });
+        }
+        Ok(())
+    }
+
+    fn validate_price(&self, rate: P) -> Result<(), Rejection<P, Q>> {
+        if rate < self.min_price {
+            return Err(Rejection::PriceBelowMin {
+                rate,
+                min_price: self.min_price,
+            });
+        }
+        if rate > self.max_price {
+            return Err(Rejection::PriceAboveMax {
+                rate,
+                max_price: self.max_price,
+            });
+        }
+        if rate.to_raw().rem_euclid(self.tick_size.to_raw()) != 0 {
+            return Err(Rejection::OffTick {
+                rate,
+                tick_size: self.tick_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Price of decimal text, None if it is malformed, finer than price scale or out of range
+    pub fn parse_price(&self, text: &str) -> Option<P> {
+        let raw = parse_fixed(text, self.price_scale)?;
+        P::from_raw(i64::try_from(raw).ok()?)
+    }
+
+    /// Quantity of decimal text, None if it is malformed, finer than quantity scale or out of range
+    pub fn parse_quantity(&self, text: &str) -> Option<Q> {
+        let raw = parse_fixed(text, self.quantity_scale)?;
+        Q::from_raw(u64::try_from(raw).ok()?)
+    }
+
+    pub fn format_price(&self, rate: P) -> String {
+        format_fixed(rate.to_raw() as i128, self.price_scale)
+    }
+
+    pub fn format_quantity(&self, quantity: Q) -> String {
+        format_fixed(quantity.to_raw() as i128, self.quantity_scale)
+    }
+
+    /// Notional is scaled by both price and quantity scale
+    pub fn format_notional(&self, notional: i128) -> String {
+        format_fixed(notional, self.price_scale + self.quantity_scale)
+    }
+}
+
+impl<P: PriceValue, Q: QuantityValue> Display for Rejection<P, Q> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::OffTick { rate, tick_size } => {
+                write!(
+                    f,
+                    "price {} is not multiple of tick size {}",
+                    rate, tick_size
+                )
+            }
+            Rejection::OddLot { quantity, lot_size } => write!(
+                f,
+                "quantity {} is not multiple of lot size {}",
+                quantity, lot_size
+            ),
+            Rejection::QuantityBelowMin {
+                quantity,
+                min_quantity,
+            } => write!(f, "quantity {} is below minimum {}", quantity, min_quantity),
+            Rejection::QuantityAboveMax {
+                quantity,
+                max_quantity,
+            } => write!(f, "quantity {} is above maximum {}", quantity, max_quantity),
+            Rejection::PriceBelowMin { rate, min_price } => {
+                write!(f, "price {} is below minimum {}", rate, min_price)
+            }
+            Rejection::PriceAboveMax { rate, max_price } => {
+                write!(f, "price {} is above maximum {}", rate, max_price)
+            }
+            Rejection::NotionalAboveMax {
+                notional,
+                max_notional,
+            } => write!(f, "notional {} is above maximum {}", notional, max_notional),
+        }
+    }
+}
+
+impl<P: PriceValue, Q: QuantityValue> std::error::Error for Rejection<P, Q> {}
+
+impl<P: PriceValue, Q: QuantityValue> Display for SpecError<P, Q> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SpecError::NonPositiveTickSize { tick_size } => {
+                write!(f, "tick size {} is not positive", tick_size)
+            }
+            SpecError::ZeroLotSize => write!(f, "lot size is zero"),
+            SpecError::EmptyPriceRange {
+                min_price,
+                max_price,
+            } => write!(
+                f,
+                "minimum price {} is above maximum {}",
+                min_price, max_price
+            ),
+            SpecError::EmptyQuantityRange {
+                min_quantity,
+                max_quantity,
+            } => write!(
+                f,
+                "minimum quantity {} is above maximum {}",
+                min_quantity, max_quantity
+            ),
+            SpecError::TooManyTicks { ticks, max_ticks } => write!(
+                f,
+                "price range of {} ticks is wider than {} ticks book holds",
+                ticks, max_ticks
+            ),
+        }
+    }
+}
+
+impl<P: PriceValue, Q: QuantityValue> std::error::Error for SpecError<P, Q> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orders::OrderType;
+
+    #[test]
+    fn validate_spec() {
+        assert_eq!(
+            InstrumentSpec::<Price, Quantity>::default().validate(),
+            Ok(())
+        );
+        let cases: [(InstrumentSpec, SpecError); 4] = [
+            (
+                InstrumentSpec {
+                    tick_size: 0,
+                    ..Default::default()
+                },
+                SpecError::NonPositiveTickSize { tick_size: 0 },
+            ),
+            (
+                InstrumentSpec {
+                    lot_size: 0,
+                    ..Default::default()
+                },
+                SpecError::ZeroLotSize,
+            ),
+            (
+                InstrumentSpec {
+                    min_price: 200,
+                    max_price: 100,
+                    ..Default::default()
+                },
+                SpecError::EmptyPriceRange {
+                    min_price: 200,
+                    max_price: 100,
+                },
+            ),
+            (
+                InstrumentSpec {
+                    min_quantity: 20,
+                    max_quantity: 10,
+                    ..Default::default()
+                },
+                SpecError::EmptyQuantityRange {
+                    min_quantity: 20,
+                    max_quantity: 10,
+                },
+            ),
+        ];
+        for (spec, error) in cases {
+            assert_eq!(spec.validate(), Err(error));
+        }
+    }
+
+    #[test]
+    fn validate_order() {
+        let spec = InstrumentSpec {
+            tick_size: 5,
+            lot_size: 10,
+            min_quantity: 10,
+            max_quantity: 1_000,
+            min_price: 50,
+            max_price: 200,
+            max_notional: 50_000,
+            ..Default::default()
+        };
+        let limit = |rate, quantity| Order::limit(0, OrderType::Buy, rate, quantity);
+        assert_eq!(spec.validate_order(&limit(100, 100)), Ok(()));
+        assert_eq!(
+            spec.validate_order(&Order::market(0, OrderType::Sell, 500)),
+            Ok(())
+        );
+        for (order, rejection) in [
+            (
+                limit(101, 100),
+                Rejection::OffTick {
+                    rate: 101,
+                    tick_size: 5,
+                },
+            ),
+            (
+                limit(100, 105),
+                Rejection::OddLot {
+                    quantity: 105,
+                    lot_size: 10,
+                },
+            ),
+            (
+                limit(100, 100).with_peak(15),
+                Rejection::OddLot {
+                    quantity: 15,
+                    lot_size: 10,
+                },
+            ),
+            (
+                limit(100, 0),
+                Rejection::QuantityBelowMin {
+                    quantity: 0,
+                    min_quantity: 10,
+                },
+            ),
+            (
+                limit(100, 2_000),
+                Rejection::QuantityAboveMax {
+                    quantity: 2_000,
+                    max_quantity: 1_000,
+                },
+            ),
+            (
+                limit(45, 100),
+                Rejection::PriceBelowMin {
+                    rate: 45,
+                    min_price: 50,
+                },
+            ),
+            (
+                Order::stop(0, OrderType::Buy, 205, 100),
+                Rejection::PriceAboveMax {
+                    rate: 205,
+                    max_price: 200,
+                },
+            ),
+            (
+                limit(200, 1_000),
+                Rejection::NotionalAboveMax {
+                    notional: 200_000,
+                    max_notional: 50_000,
+                },
+            ),
+        ] {
+            assert_eq!(spec.validate_order(&order), Err(rejection));
+        }
+    }
+}